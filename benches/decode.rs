@@ -34,7 +34,7 @@ fn create_list(
     seq_len: usize,
     gap_range: core::ops::Range<u32>,
 ) -> (ioqp::impact::MetaData, Vec<u8>) {
-    let mut increasing_seq = Vec::<u32>::with_capacity(seq_len as usize);
+    let mut increasing_seq = Vec::<u32>::with_capacity(seq_len);
     let mut rng = rand::thread_rng();
     let mut last: u32 = 0;
     for _ in 0..seq_len {
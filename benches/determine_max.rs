@@ -1,5 +1,3 @@
-#![feature(stdsimd)]
-
 use criterion::BenchmarkId;
 use criterion::Criterion;
 use criterion::{criterion_group, criterion_main};
@@ -43,6 +41,7 @@ fn bench_decode_impacts(c: &mut Criterion) {
     let num_scores = 256 * 256 * 256;
     let scores: Vec<i16> = score_range.sample_iter(&mut rng).take(num_scores).collect();
     let mut group = c.benchmark_group("determine_max");
+    #[cfg(target_feature = "avx2")]
     group.bench_with_input(
         BenchmarkId::new("determine_max_avx2", num_scores),
         &scores,
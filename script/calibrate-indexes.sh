@@ -0,0 +1,25 @@
+#!/usr/bin/env bash
+
+set -ex
+
+D=data
+CALIBRATE=./target/release/calibrate
+
+# fit the latency model of each index, written next to it as <index>.cost
+ioqp_calibrate() {
+    $CALIBRATE \
+        -i $1 \
+        -q $2 \
+        $3
+}
+
+# msmarco
+ioqp_calibrate $D/msmarco/indexes/bp-deepct.8.ioqp.idx $D/msmarco/queries/deepct.dev.query
+ioqp_calibrate $D/msmarco/indexes/bp-deepimpact.ioqp.idx $D/msmarco/queries/deepimpact.query
+ioqp_calibrate $D/msmarco/indexes/bp-doct5query.8.ioqp.idx $D/msmarco/queries/doct5query.dev.query
+ioqp_calibrate $D/msmarco/indexes/bp-original.8.ioqp.idx $D/msmarco/queries/original.dev.query
+ioqp_calibrate $D/msmarco/indexes/bp-spladev2.ioqp.idx $D/msmarco/queries/spladev2.dev.query --weighted
+ioqp_calibrate $D/msmarco/indexes/bp-unicoil-tilde.ioqp.idx $D/msmarco/queries/unicoil-tilde.dev.query --weighted
+
+# gov2
+ioqp_calibrate $D/gov2/indexes/bp-gov2.8.ioqp.idx $D/gov2/queries/gov2.queries
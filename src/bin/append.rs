@@ -0,0 +1,52 @@
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "append", about = "append documents to ioqp indexes")]
+struct Args {
+    /// Path to ioqp input index file
+    #[structopt(short, long, parse(from_os_str))]
+    index: std::path::PathBuf,
+    /// Path to ciff file with the new documents
+    #[structopt(long, parse(from_os_str))]
+    ciff: std::path::PathBuf,
+    /// Path to ioqp index output file
+    #[structopt(short, long, parse(from_os_str))]
+    output: std::path::PathBuf,
+    /// Merge adjacent appended segments with at most this many documents
+    #[structopt(long)]
+    merge_below: Option<usize>,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    info!("loading index from file {}", args.index.display());
+    let mut index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(&args.index)?;
+
+    info!("append documents from {}", args.ciff.display());
+    let start = std::time::Instant::now();
+    index.append_ciff_file(&args.ciff)?;
+    info!(
+        "append time: {:.2} secs, {} segments",
+        start.elapsed().as_secs_f64(),
+        index.num_segments()
+    );
+
+    if let Some(max_docs) = args.merge_below {
+        let start = std::time::Instant::now();
+        let num_segments = index.merge_small_segments(max_docs);
+        info!(
+            "merge time: {:.2} secs, {} segments",
+            start.elapsed().as_secs_f64(),
+            num_segments
+        );
+    }
+
+    info!("write index to file {}", args.output.display());
+    index.write_to_file(&args.output)?;
+
+    Ok(())
+}
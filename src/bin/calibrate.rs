@@ -0,0 +1,97 @@
+use indicatif::ProgressIterator;
+use ioqp::cost;
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "calibrate",
+    about = "fit the latency model used by target query modes"
+)]
+struct Args {
+    /// Path to ioqp input file
+    #[structopt(short, long, parse(from_os_str))]
+    index: std::path::PathBuf,
+    /// Path to query file
+    #[structopt(short, long, parse(from_os_str))]
+    queries: std::path::PathBuf,
+    /// Top-k depth
+    #[structopt(short, long, default_value = "10")]
+    k: std::num::NonZeroUsize,
+    /// Fractions of the postings of each query to measure
+    #[structopt(
+        long,
+        use_delimiter = true,
+        default_value = "0.01,0.02,0.05,0.1,0.2,0.5,1.0"
+    )]
+    fractions: Vec<f32>,
+    /// Runs per measurement, the fastest one is used
+    #[structopt(long, default_value = "3")]
+    repetitions: std::num::NonZeroUsize,
+    /// Where to write the model, next to the index by default
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<std::path::PathBuf>,
+    /// Whether or not to obey query weights
+    #[structopt(long)]
+    weighted: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    let qrys = ioqp::query::read_queries(&args.queries, args.weighted)?;
+    let index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(&args.index)?;
+    let k = usize::from(args.k);
+
+    let pb = ioqp::util::progress_bar("warmup", qrys.len());
+    for qry in qrys.iter().progress_with(pb) {
+        index.query_fraction(&qry.tokens, 1.0, None, k);
+    }
+
+    let mut samples = Vec::with_capacity(qrys.len() * args.fractions.len());
+    let pb = ioqp::util::progress_bar("measure queries", qrys.len());
+    for qry in qrys.iter().progress_with(pb) {
+        let group_counts = index.impact_group_counts(&qry.tokens);
+        let total_postings: u64 = group_counts.iter().map(|&count| u64::from(count)).sum();
+        for &rho in &args.fractions {
+            let budget = (total_postings as f32 * rho).ceil() as i64;
+            let (groups, postings) = cost::processed(&group_counts, budget);
+            // the run with the fastest accumulation is the least disturbed one
+            let results = (0..args.repetitions.get())
+                .map(|_| index.query_fixed(&qry.tokens, budget, None, k))
+                .min_by_key(|results| results.stats.accumulation)
+                .expect("at least one repetition");
+            let micros = |took: std::time::Duration| took.as_secs_f64() * 1_000_000.0;
+            let accumulation_micros = micros(results.stats.accumulation);
+            samples.push(cost::Sample {
+                groups,
+                postings,
+                accumulation_micros,
+                overhead_micros: micros(results.took) - accumulation_micros,
+            });
+        }
+    }
+
+    let model = cost::CostModel::fit(&samples)?;
+    let mean_error = samples
+        .iter()
+        .map(|s| {
+            let micros = s.accumulation_micros + s.overhead_micros;
+            (model.estimate(s.groups, s.postings).as_secs_f64() * 1_000_000.0 - micros).abs()
+        })
+        .sum::<f64>()
+        / samples.len() as f64;
+    info!("fitted {:?} to {} samples", model, samples.len());
+    info!("mean absolute error: {:.1}µs", mean_error);
+
+    let output = match args.output {
+        Some(output) => output,
+        None => cost::sidecar_path(&args.index),
+    };
+    info!("write cost model to file {}", output.display());
+    model.write_to_file(output)?;
+
+    Ok(())
+}
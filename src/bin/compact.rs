@@ -0,0 +1,38 @@
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "compact",
+    about = "rewrite ioqp indexes without deleted documents"
+)]
+struct Args {
+    /// Path to ioqp input index file
+    #[structopt(short, long, parse(from_os_str))]
+    input: std::path::PathBuf,
+    /// Path to ioqp output index file
+    #[structopt(short, long, parse(from_os_str))]
+    output: std::path::PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    info!("loading index from file {}", args.input.display());
+    let index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(&args.input)?;
+    info!("index has {} deleted docs", index.num_deleted());
+
+    let start = std::time::Instant::now();
+    let index = index.compact();
+    info!(
+        "index compaction time: {:.2} secs",
+        start.elapsed().as_secs_f64()
+    );
+
+    info!("write index to file {}", args.output.display());
+    index.write_to_file(args.output)?;
+
+    Ok(())
+}
@@ -22,6 +22,9 @@ struct Args {
     /// Number of bits to use for index quantization
     #[structopt(short, long, default_value = "8")]
     quant_bits: u32,
+    /// Also write a forward index for exact re-scoring to this file
+    #[structopt(long, parse(from_os_str))]
+    forward_index: Option<std::path::PathBuf>,
 }
 
 fn main() -> anyhow::Result<()> {
@@ -31,21 +34,16 @@ fn main() -> anyhow::Result<()> {
 
     info!("create ioqp index from {}", args.input.display());
     let start = std::time::Instant::now();
-    let index = if args.quantize {
-        let scorer = ioqp::score::BM25::new(args.bm25_k1, args.bm25_b);
-        ioqp::Index::<ioqp::SimdBPandStreamVbyte>::from_ciff_file(
-            args.input,
-            args.quant_bits,
-            scorer,
-        )
+    let scorer = if args.quantize {
+        ioqp::score::IndexScorer::BM25(ioqp::score::BM25::new(args.bm25_k1, args.bm25_b))
     } else {
-        let scorer = ioqp::score::Identity::new();
-        ioqp::Index::<ioqp::SimdBPandStreamVbyte>::from_ciff_file(
-            args.input,
-            args.quant_bits,
-            scorer,
-        )
-    }?;
+        ioqp::score::IndexScorer::Identity(ioqp::score::Identity::new())
+    };
+    let index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::from_ciff_file(
+        &args.input,
+        args.quant_bits,
+        scorer,
+    )?;
     info!(
         "index creation time: {:.2} secs",
         start.elapsed().as_secs_f64()
@@ -59,5 +57,16 @@ fn main() -> anyhow::Result<()> {
         start.elapsed().as_secs_f64()
     );
 
+    if let Some(forward_index) = &args.forward_index {
+        info!("write forward index to file {}", forward_index.display());
+        let start = std::time::Instant::now();
+        ioqp::forward::ForwardIndex::from_ciff_file(&args.input, scorer)?
+            .write_to_file(forward_index)?;
+        info!(
+            "forward index creation time: {:.2} secs",
+            start.elapsed().as_secs_f64()
+        );
+    }
+
     Ok(())
 }
@@ -0,0 +1,43 @@
+use std::io::BufRead;
+
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "delete", about = "delete documents from ioqp indexes")]
+struct Args {
+    /// Path to ioqp index file
+    #[structopt(short, long, parse(from_os_str))]
+    index: std::path::PathBuf,
+    /// Path to file with one collection docid per line
+    #[structopt(short, long, parse(from_os_str))]
+    docids: std::path::PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    let docids_file = std::io::BufReader::new(std::fs::File::open(&args.docids)?);
+    let collection_doc_ids = docids_file
+        .lines()
+        .collect::<Result<Vec<String>, _>>()?
+        .into_iter()
+        .filter(|l| !l.trim().is_empty())
+        .collect::<Vec<_>>();
+    info!("read {} docids to delete", collection_doc_ids.len());
+
+    info!("loading index from file {}", args.index.display());
+    let index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(&args.index)?;
+
+    let num_deleted = index.delete_collection_doc_ids(&collection_doc_ids);
+    info!(
+        "deleted {} new docs, {} docs deleted in total",
+        num_deleted,
+        index.num_deleted()
+    );
+    index.write_deletions(&args.index)?;
+
+    Ok(())
+}
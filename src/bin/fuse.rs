@@ -0,0 +1,57 @@
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "fuse", about = "fuse TREC runs into one")]
+struct Args {
+    /// TREC run files to fuse
+    #[structopt(parse(from_os_str), required = true, min_values = 2)]
+    runs: Vec<std::path::PathBuf>,
+    /// Fusion method: rrf, combsum or combmnz
+    #[structopt(short, long, default_value = "rrf")]
+    method: String,
+    /// Score normalization of combsum and combmnz: minmax or zscore
+    #[structopt(long, default_value = "minmax")]
+    normalization: ioqp::fusion::Normalization,
+    /// Rank constant of reciprocal rank fusion
+    #[structopt(long, default_value = "60")]
+    rrf_k: f64,
+    /// Number of fused documents written per query
+    #[structopt(short, long, default_value = "1000")]
+    depth: usize,
+    /// trec output file
+    #[structopt(short, long, parse(from_os_str))]
+    output_file: std::path::PathBuf,
+    /// Run tag of the fused run
+    #[structopt(long, default_value = "ioqp-fused")]
+    tag: String,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    let fusion = match args.method.as_str() {
+        "rrf" => ioqp::fusion::Fusion::Rrf { k: args.rrf_k },
+        "combsum" => ioqp::fusion::Fusion::CombSum(args.normalization),
+        "combmnz" => ioqp::fusion::Fusion::CombMnz(args.normalization),
+        _ => anyhow::bail!("method must be rrf, combsum or combmnz"),
+    };
+
+    let runs = args
+        .runs
+        .iter()
+        .map(|run| {
+            info!("reading run {}", run.display());
+            ioqp::eval::Run::read_from_file(run)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let fused = fusion.fuse_runs(&runs, args.depth);
+    info!("write fused run to {}", args.output_file.display());
+    let output = std::io::BufWriter::new(std::fs::File::create(&args.output_file)?);
+    fused.write_trec(output, &args.tag)?;
+
+    Ok(())
+}
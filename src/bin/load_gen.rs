@@ -1,37 +1,6 @@
 use structopt::StructOpt;
 use tracing::*;
 
-#[derive(serde::Serialize, Debug, Clone, Copy)]
-enum QueryMode {
-    Fraction(f32),
-    Fixed(u64),
-}
-
-impl std::str::FromStr for QueryMode {
-    type Err = anyhow::Error;
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split('-').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("invalid query mode"));
-        }
-        match parts[0] {
-            "fraction" => {
-                let rho = parts[1].parse::<f32>()?;
-                if (0.0..=1.0).contains(&rho) {
-                    Ok(QueryMode::Fraction(rho))
-                } else {
-                    Err(anyhow::anyhow!("Rho must be in range [0.0, 1.0]"))
-                }
-            }
-            "fixed" => {
-                let budget = parts[1].parse::<u64>()?;
-                Ok(QueryMode::Fixed(budget))
-            }
-            _ => Err(anyhow::anyhow!("invalid query mode")),
-        }
-    }
-}
-
 #[derive(StructOpt, Debug)]
 #[structopt(name = "query", about = "query ioqp indexes")]
 struct Args {
@@ -41,9 +10,10 @@ struct Args {
     /// Path to query file
     #[structopt(short, long, parse(from_os_str))]
     queries: std::path::PathBuf,
-    /// Query mode
+    /// Query mode: fraction-<rho>, fixed-<postings>, deadline-<millis>, target-<millis>,
+    /// termcap-<postings>, segments-<count>, minimpact-<impact> or safe
     #[structopt(short, long)]
-    mode: QueryMode,
+    mode: ioqp::QueryMode,
     /// Top-k depth
     #[structopt(short, long, default_value = "10")]
     k: std::num::NonZeroUsize,
@@ -62,7 +32,7 @@ struct Args {
 struct QueryPayLoad {
     query: ioqp::query::Query,
     k: std::num::NonZeroUsize,
-    query_mode: QueryMode,
+    query_mode: ioqp::QueryMode,
 }
 
 async fn process_query(
@@ -128,7 +98,7 @@ async fn main() -> anyhow::Result<()> {
     let mut user_hist = Vec::new();
     let limit = total_req_duration.as_secs() * args.tps.get() as u64;
     let pb = indicatif::ProgressBar::new(limit);
-    pb.set_draw_delta(total_req_duration.as_secs() as u64 / 200);
+    pb.set_draw_delta(total_req_duration.as_secs() / 200);
     pb.set_style(indicatif::ProgressStyle::default_bar().template(&format!(
         "{}: {}",
         "running query load",
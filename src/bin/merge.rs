@@ -0,0 +1,40 @@
+use structopt::StructOpt;
+use tracing::info;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "merge", about = "merge ioqp indexes into a single index")]
+struct Args {
+    /// Paths to ioqp input index files, merged in the given order
+    #[structopt(short, long, parse(from_os_str), required = true)]
+    input: Vec<std::path::PathBuf>,
+    /// Path to ioqp output index file
+    #[structopt(short, long, parse(from_os_str))]
+    output: std::path::PathBuf,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::from_args();
+    info!("args = {:?}", &args);
+
+    let indexes = args
+        .input
+        .iter()
+        .map(|path| {
+            info!("loading index from file {}", path.display());
+            ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(path)
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let start = std::time::Instant::now();
+    let index = ioqp::Index::merge(&indexes)?;
+    info!(
+        "index merge time: {:.2} secs",
+        start.elapsed().as_secs_f64()
+    );
+
+    info!("write index to file {}", args.output.display());
+    index.write_to_file(&args.output)?;
+
+    Ok(())
+}
@@ -1,34 +1,86 @@
 use indicatif::ProgressIterator;
+use std::io::Write;
 use structopt::StructOpt;
 
+/// Format of the per-query log
 #[derive(Debug)]
-enum QueryMode {
-    Fraction(f32),
-    Fixed(u64),
+enum LogFormat {
+    Csv,
+    Jsonl,
 }
 
-impl std::str::FromStr for QueryMode {
+impl std::str::FromStr for LogFormat {
     type Err = anyhow::Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let parts: Vec<&str> = s.split('-').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("invalid query mode"));
-        }
-        match parts[0] {
-            "fraction" => {
-                let rho = parts[1].parse::<f32>()?;
-                if (0.0..=1.0).contains(&rho) {
-                    Ok(QueryMode::Fraction(rho))
-                } else {
-                    Err(anyhow::anyhow!("Rho must be in range [0.0, 1.0]"))
-                }
-            }
-            "fixed" => {
-                let budget = parts[1].parse::<u64>()?;
-                Ok(QueryMode::Fixed(budget))
+        match s {
+            "csv" => Ok(LogFormat::Csv),
+            "jsonl" => Ok(LogFormat::Jsonl),
+            _ => Err(anyhow::anyhow!("log format must be csv or jsonl")),
+        }
+    }
+}
+
+/// One row of the per-query log
+#[derive(serde::Serialize)]
+struct QueryLogRow {
+    qid: usize,
+    num_terms: usize,
+    total_postings: usize,
+    processed_postings: usize,
+    processed_segments: usize,
+    budget_exhausted: bool,
+    matched: usize,
+    took_micros: u128,
+    planning_micros: u128,
+    accumulation_micros: u128,
+    topk_micros: u128,
+}
+
+impl QueryLogRow {
+    const CSV_HEADER: &'static str = "qid,num_terms,total_postings,processed_postings,\
+        processed_segments,budget_exhausted,matched,took_micros,planning_micros,\
+        accumulation_micros,topk_micros";
+
+    fn new(qry: &ioqp::query::Query, results: &ioqp::Results) -> Self {
+        let stats = &results.stats;
+        Self {
+            qid: qry.id,
+            num_terms: qry.tokens.len(),
+            total_postings: stats.total_postings,
+            processed_postings: stats.processed_postings,
+            processed_segments: stats.processed_segments,
+            budget_exhausted: stats.budget_exhausted,
+            matched: results.matched,
+            took_micros: results.took.as_micros(),
+            planning_micros: stats.planning.as_micros(),
+            accumulation_micros: stats.accumulation.as_micros(),
+            topk_micros: stats.topk.as_micros(),
+        }
+    }
+
+    fn write(&self, format: &LogFormat, mut output: impl Write) -> anyhow::Result<()> {
+        match format {
+            LogFormat::Csv => writeln!(
+                output,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                self.qid,
+                self.num_terms,
+                self.total_postings,
+                self.processed_postings,
+                self.processed_segments,
+                self.budget_exhausted,
+                self.matched,
+                self.took_micros,
+                self.planning_micros,
+                self.accumulation_micros,
+                self.topk_micros
+            )?,
+            LogFormat::Jsonl => {
+                serde_json::to_writer(&mut output, self)?;
+                writeln!(output)?;
             }
-            _ => Err(anyhow::anyhow!("invalid query mode")),
         }
+        Ok(())
     }
 }
 
@@ -41,16 +93,19 @@ struct Args {
     /// Path to query file
     #[structopt(short, long, parse(from_os_str))]
     queries: std::path::PathBuf,
-    /// Query mode
-    #[structopt(short, long)]
-    mode: QueryMode,
-    /// Top-k depth
-    #[structopt(short, long, default_value = "10")]
-    k: std::num::NonZeroUsize,
+    /// Query mode: fraction-<rho>, fixed-<postings>, deadline-<millis>, target-<millis>,
+    /// termcap-<postings>, segments-<count>, minimpact-<impact> or safe
+    ///
+    /// Several comma separated modes run one after another, each writing its own run file.
+    #[structopt(short, long, use_delimiter = true, required = true)]
+    mode: Vec<ioqp::QueryMode>,
+    /// Top-k depth, or comma separated depths to run each mode with
+    #[structopt(short, long, use_delimiter = true, default_value = "10")]
+    k: Vec<std::num::NonZeroUsize>,
     /// num_queries to run
     #[structopt(short, long)]
     num_queries: Option<usize>,
-    /// trec output file
+    /// trec output file, with the mode and depth added to the name when running several
     #[structopt(short, long)]
     output_file: std::path::PathBuf,
     /// touch term postings present in queryfile
@@ -59,16 +114,73 @@ struct Args {
     /// Whether or not to obey query weights
     #[structopt(long)]
     weighted: bool,
+    /// Number of document ranges each query is processed on in parallel
+    #[structopt(long, default_value = "1")]
+    parallelism: std::num::NonZeroUsize,
+    /// Static document priors that break score ties, one `<docid> <prior>` per line
+    #[structopt(long, parse(from_os_str))]
+    priors: Option<std::path::PathBuf>,
+    /// Write approximate original scores instead of quantized ones
+    #[structopt(long)]
+    real_scores: bool,
+    /// Write one line of statistics per query to this file
+    #[structopt(long, parse(from_os_str))]
+    query_log: Option<std::path::PathBuf>,
+    /// Format of the query log: csv or jsonl
+    #[structopt(long, default_value = "csv")]
+    query_log_format: LogFormat,
+    /// Number of queries processed concurrently
+    #[structopt(long, default_value = "1")]
+    threads: std::num::NonZeroUsize,
+    /// TREC qrels to evaluate the results with
+    #[structopt(long, parse(from_os_str))]
+    qrels: Option<std::path::PathBuf>,
+    /// Depth of nDCG, recall and precision, the top-k depth if not given
+    #[structopt(long)]
+    eval_depth: Option<usize>,
+    /// Write the evaluation of each query to this file, in the format of the query log
+    #[structopt(long, parse(from_os_str))]
+    eval_output: Option<std::path::PathBuf>,
+    /// Compare the top-k with that of exhaustive processing, computed for each query
+    #[structopt(long, conflicts_with = "reference-run")]
+    compare_exhaustive: bool,
+    /// Compare the top-k with that of a reference TREC run
+    #[structopt(long, parse(from_os_str))]
+    reference_run: Option<std::path::PathBuf>,
+    /// Persistence of the rank-biased overlap with the reference
+    #[structopt(long, default_value = "0.9")]
+    rbo_p: f64,
+    /// Write the comparison of each query with the reference to this file, in the format
+    /// of the query log
+    #[structopt(long, parse(from_os_str))]
+    approx_output: Option<std::path::PathBuf>,
+    /// Forward index to score the top candidates of each query exactly with
+    #[structopt(long, parse(from_os_str))]
+    forward_index: Option<std::path::PathBuf>,
+    /// Number of candidates scored exactly, the top-k depth if not given
+    #[structopt(long)]
+    rescore_depth: Option<usize>,
 }
 
 fn main() -> anyhow::Result<()> {
     let args = Args::from_args();
 
-    let qrys = ioqp::query::read_queries(args.queries, args.weighted)?;
-
-    let index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(args.index)?;
+    let qrys = ioqp::query::read_queries(&args.queries, args.weighted)?;
+    let mut evaluators = Evaluators {
+        qrels: match &args.qrels {
+            Some(qrels) => Some(ioqp::eval::Qrels::read_from_file(qrels)?),
+            None => None,
+        },
+        reference: match &args.reference_run {
+            Some(run) => Some(ioqp::eval::Run::read_from_file(run)?),
+            None => None,
+        },
+    };
 
-    let out_handle = std::fs::File::create(args.output_file).expect("can not open output file");
+    let mut index = ioqp::Index::<ioqp::SimdBPandStreamVbyte>::read_from_file(&args.index)?;
+    if let Some(priors) = &args.priors {
+        index.read_priors(priors)?;
+    }
 
     let num_queries = match args.num_queries {
         Some(num_queries) => num_queries,
@@ -83,41 +195,427 @@ fn main() -> anyhow::Result<()> {
             index.query_warmup(std::slice::from_ref(t));
         }
     }
-    let mut hist = Vec::with_capacity(num_queries);
-    let pb = ioqp::util::progress_bar("process_queries", num_queries);
-    match args.mode {
-        QueryMode::Fraction(rho) => {
-            for qry in qrys.iter().cycle().take(num_queries).progress_with(pb) {
-                let result =
-                    index.query_fraction(&qry.tokens, rho, Some(qry.id), usize::from(args.k));
-                hist.push(result.took.as_micros() as u64);
-                result.to_trec_file(index.docmap(), &out_handle);
+    let forward = match &args.forward_index {
+        Some(path) => {
+            let forward = ioqp::forward::ForwardIndex::read_from_file(path)?;
+            if forward.num_docs() != index.docmap().len() {
+                anyhow::bail!("the forward index does not have the documents of the index");
+            }
+            Some(forward)
+        }
+        None => None,
+    };
+    let engine = Engine {
+        threads: args.threads.get(),
+        partition_pool: ioqp::util::partition_pool(args.threads.get(), args.parallelism.get())?,
+        forward,
+    };
+    let batch: Vec<_> = qrys.iter().cycle().take(num_queries).collect();
+
+    if args.compare_exhaustive {
+        let max_k = args.k.iter().max().map_or(0, |k| k.get());
+        let (mode, parallelism) = (ioqp::QueryMode::Fraction(1.0), args.parallelism.get());
+        let pb = ioqp::util::progress_bar("exhaustive_reference", qrys.len());
+        let qrys: Vec<_> = qrys.iter().collect();
+        let results = engine.process(&qrys, &pb, |qry| {
+            engine.query(&index, qry, mode, max_k, parallelism)
+        });
+        let mut reference = ioqp::eval::Run::default();
+        for (qry, mut result) in qrys.iter().zip(results) {
+            if args.real_scores {
+                index.add_real_scores(&mut result, qry.weight_scale);
             }
+            reference.insert(qry.id, ioqp::eval::ranking(&result, index.docmap()));
         }
-        QueryMode::Fixed(budget) => {
-            for qry in qrys.iter().cycle().take(num_queries).progress_with(pb) {
-                let result = index.query_fixed(
-                    &qry.tokens,
-                    budget as i64,
-                    Some(qry.id),
-                    usize::from(args.k),
-                );
-                hist.push(result.took.as_micros() as u64);
-                result.to_trec_file(index.docmap(), &out_handle);
+        evaluators.reference = Some(reference);
+    }
+
+    let configs: Vec<(ioqp::QueryMode, usize)> = args
+        .mode
+        .iter()
+        .flat_map(|&mode| args.k.iter().map(move |&k| (mode, k.get())))
+        .collect();
+    let sweep = configs.len() > 1;
+    let mut summaries = Vec::with_capacity(configs.len());
+    for &(mode, k) in &configs {
+        // each configuration of a sweep gets its own output files
+        let config_path = |path: &std::path::Path| {
+            if sweep {
+                config_path(path, mode, k)
+            } else {
+                path.to_path_buf()
             }
+        };
+        if sweep {
+            println!("mode: {}, k: {}", mode, k);
         }
+        let outputs = Outputs {
+            run: config_path(&args.output_file),
+            query_log: args.query_log.as_deref().map(config_path),
+            eval: args.eval_output.as_deref().map(config_path),
+            approx: args.approx_output.as_deref().map(config_path),
+        };
+        let summary = run_config(
+            &index,
+            &engine,
+            &batch,
+            (mode, k),
+            &args,
+            &evaluators,
+            &outputs,
+        )?;
+        summary.print();
+        summaries.push(summary);
     }
 
-    hist.sort_unstable();
-    let n = hist.len() as f32;
-    let total_time = hist.iter().sum::<u64>();
-    println!("# of samples: {}", hist.len());
-    println!("  50'th percntl.: {}µs", hist[(n * 0.5) as usize]);
-    println!("  90'th percntl.: {}µs", hist[(n * 0.9) as usize]);
-    println!("  99'th percntl.: {}µs", hist[(n * 0.99) as usize]);
-    println!("99.9'th percntl.: {}µs", hist[(n * 0.999) as usize]);
-    println!("            max.: {}µs", hist.last().unwrap());
-    println!("       mean time: {:.1}µs", total_time as f32 / n);
+    if sweep {
+        println!();
+        print!(
+            "{:<20} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+            "mode", "k", "mean(µs)", "p50(µs)", "p90(µs)", "p99(µs)", "max(µs)", "QPS"
+        );
+        if evaluators.qrels.is_some() {
+            print!(
+                " {:>8} {:>8} {:>8} {:>8} {:>8}",
+                "nDCG", "MAP", "MRR@10", "R", "P"
+            );
+        }
+        if evaluators.reference.is_some() {
+            print!(" {:>8} {:>8} {:>8}", "overlap", "RBO", "mass");
+        }
+        println!();
+        for summary in &summaries {
+            summary.print_row();
+        }
+    }
 
     Ok(())
 }
+
+/// Adds the query mode and depth to a file name, e.g. `run.fixed-1000.k10.trec` for `run.trec`
+fn config_path(path: &std::path::Path, mode: ioqp::QueryMode, k: usize) -> std::path::PathBuf {
+    let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+    file_name.push(format!(".{}.k{}", mode, k));
+    if let Some(extension) = path.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+/// Output files of one configuration
+struct Outputs {
+    run: std::path::PathBuf,
+    query_log: Option<std::path::PathBuf>,
+    eval: Option<std::path::PathBuf>,
+    approx: Option<std::path::PathBuf>,
+}
+
+/// Latencies and effectiveness of the queries of one configuration
+struct Summary {
+    mode: ioqp::QueryMode,
+    k: usize,
+    // sorted query latencies in microseconds
+    hist: Vec<u64>,
+    elapsed: std::time::Duration,
+    // mean evaluation of the judged queries and the depth it was computed at
+    eval: Option<(ioqp::eval::Evaluation, usize)>,
+    // mean agreement of the queries with a reference ranking
+    approx: Option<ioqp::eval::Approximation>,
+}
+
+impl Summary {
+    fn percentile(&self, p: f64) -> u64 {
+        ioqp::util::percentile(&self.hist, p).unwrap_or_default()
+    }
+
+    fn mean(&self) -> f64 {
+        self.hist.iter().sum::<u64>() as f64 / self.hist.len() as f64
+    }
+
+    fn qps(&self) -> f64 {
+        self.hist.len() as f64 / self.elapsed.as_secs_f64()
+    }
+
+    fn print(&self) {
+        println!("# of samples: {}", self.hist.len());
+        if self.hist.is_empty() {
+            return;
+        }
+        println!("  50'th percntl.: {}µs", self.percentile(0.5));
+        println!("  90'th percntl.: {}µs", self.percentile(0.9));
+        println!("  99'th percntl.: {}µs", self.percentile(0.99));
+        println!("99.9'th percntl.: {}µs", self.percentile(0.999));
+        println!("            max.: {}µs", self.percentile(1.0));
+        println!("       mean time: {:.1}µs", self.mean());
+        println!("      throughput: {:.1} QPS", self.qps());
+        if let Some((eval, depth)) = &self.eval {
+            println!("  judged queries: {}", eval.qid);
+            println!("{:>16}: {:.4}", format!("nDCG@{}", depth), eval.ndcg);
+            println!("{:>16}: {:.4}", "MAP", eval.ap);
+            println!("{:>16}: {:.4}", "MRR@10", eval.rr);
+            println!("{:>16}: {:.4}", format!("Recall@{}", depth), eval.recall);
+            println!("{:>16}: {:.4}", format!("P@{}", depth), eval.precision);
+        }
+        if let Some(approx) = &self.approx {
+            println!("     ref queries: {}", approx.qid);
+            println!(
+                "{:>16}: {:.4}",
+                format!("overlap@{}", self.k),
+                approx.overlap
+            );
+            println!("{:>16}: {:.4}", format!("RBO@{}", self.k), approx.rbo);
+            println!("{:>16}: {:.4}", "mass recall", approx.mass_recall);
+        }
+    }
+
+    fn print_row(&self) {
+        if self.hist.is_empty() {
+            println!("{:<20} {:>6} {:>10}", self.mode.to_string(), self.k, "-");
+            return;
+        }
+        print!(
+            "{:<20} {:>6} {:>10.1} {:>10} {:>10} {:>10} {:>10} {:>10.1}",
+            self.mode.to_string(),
+            self.k,
+            self.mean(),
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(1.0),
+            self.qps()
+        );
+        if let Some((eval, _)) = &self.eval {
+            print!(
+                " {:>8.4} {:>8.4} {:>8.4} {:>8.4} {:>8.4}",
+                eval.ndcg, eval.ap, eval.rr, eval.recall, eval.precision
+            );
+        }
+        if let Some(approx) = &self.approx {
+            print!(
+                " {:>8.4} {:>8.4} {:>8.4}",
+                approx.overlap, approx.rbo, approx.mass_recall
+            );
+        }
+        println!();
+    }
+}
+
+/// Opens a per-query output file, starting it with a header in the csv format
+fn per_query_output(
+    path: &std::path::Path,
+    format: &LogFormat,
+    csv_header: &str,
+) -> anyhow::Result<std::io::BufWriter<std::fs::File>> {
+    let mut output = std::io::BufWriter::new(std::fs::File::create(path)?);
+    if let LogFormat::Csv = format {
+        writeln!(output, "{}", csv_header)?;
+    }
+    Ok(output)
+}
+
+/// How the queries are processed besides the index
+struct Engine {
+    /// Number of queries processed at once
+    threads: usize,
+    /// Runs the partitions of queries processed with a parallelism above one
+    partition_pool: Option<rayon::ThreadPool>,
+    forward: Option<ioqp::forward::ForwardIndex>,
+}
+
+impl Engine {
+    /// Applies `op` to the queries on `threads` threads that each take the next query when done,
+    /// and returns the outputs in query order
+    ///
+    /// Waiting for a rayon pool would let a thread start further queries, plain threads keep no
+    /// more than `threads` queries in flight.
+    fn process<R: Send>(
+        &self,
+        batch: &[&ioqp::query::Query],
+        pb: &indicatif::ProgressBar,
+        op: impl Fn(&ioqp::query::Query) -> R + Sync,
+    ) -> Vec<R> {
+        let next = std::sync::atomic::AtomicUsize::new(0);
+        let mut outputs: Vec<(usize, R)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut outputs = Vec::new();
+                        loop {
+                            let idx = next.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                            let Some(qry) = batch.get(idx) else {
+                                return outputs;
+                            };
+                            outputs.push((idx, op(qry)));
+                            pb.inc(1);
+                        }
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| worker.join().expect("query thread panicked"))
+                .collect()
+        });
+        outputs.sort_unstable_by_key(|(idx, _)| *idx);
+        outputs.into_iter().map(|(_, output)| output).collect()
+    }
+
+    /// Processes a query, with its partitions on the partition pool
+    fn query(
+        &self,
+        index: &ioqp::Index<ioqp::SimdBPandStreamVbyte>,
+        qry: &ioqp::query::Query,
+        mode: ioqp::QueryMode,
+        k: usize,
+        parallelism: usize,
+    ) -> ioqp::Results {
+        let query = || index.query_parallel(&qry.tokens, mode, Some(qry.id), k, None, parallelism);
+        match &self.partition_pool {
+            Some(pool) => pool.install(query),
+            None => query(),
+        }
+    }
+}
+
+/// What the results of a configuration are evaluated against
+struct Evaluators {
+    qrels: Option<ioqp::eval::Qrels>,
+    reference: Option<ioqp::eval::Run>,
+}
+
+/// Runs the queries with one query mode and depth, writing their run file and query log
+fn run_config(
+    index: &ioqp::Index<ioqp::SimdBPandStreamVbyte>,
+    engine: &Engine,
+    batch: &[&ioqp::query::Query],
+    (mode, k): (ioqp::QueryMode, usize),
+    args: &Args,
+    evaluators: &Evaluators,
+    outputs: &Outputs,
+) -> anyhow::Result<Summary> {
+    let format = &args.query_log_format;
+    let out_handle = std::fs::File::create(&outputs.run).expect("can not open output file");
+    let mut query_log = match &outputs.query_log {
+        Some(path) => Some(per_query_output(path, format, QueryLogRow::CSV_HEADER)?),
+        None => None,
+    };
+    let mut eval_output = match &outputs.eval {
+        Some(path) => Some(per_query_output(
+            path,
+            format,
+            "qid,ndcg,ap,rr,recall,precision",
+        )?),
+        None => None,
+    };
+    let mut approx_output = match &outputs.approx {
+        Some(path) => Some(per_query_output(
+            path,
+            format,
+            "qid,overlap,rbo,mass_recall",
+        )?),
+        None => None,
+    };
+
+    let parallelism = args.parallelism.get();
+    // candidates for exact scoring, of which the best k are kept
+    let depth = match engine.forward {
+        Some(_) => args.rescore_depth.map_or(k, |depth| depth.max(k)),
+        None => k,
+    };
+    let pb = ioqp::util::progress_bar("process_queries", batch.len());
+    let start = std::time::Instant::now();
+    let results = engine.process(batch, &pb, |qry| {
+        let mut results = engine.query(index, qry, mode, depth, parallelism);
+        if let Some(forward) = &engine.forward {
+            let rescore_start = std::time::Instant::now();
+            forward.rescore(&qry.tokens, &mut results, k, qry.weight_scale);
+            results.took += rescore_start.elapsed();
+        }
+        results
+    });
+    let elapsed = start.elapsed();
+
+    // results are written in query order whatever order they finished in
+    let mut hist = Vec::with_capacity(batch.len());
+    let eval_depth = args.eval_depth.unwrap_or(k);
+    let mut evaluations = Vec::new();
+    let mut approximations = Vec::new();
+    let mut evaluated = std::collections::HashSet::new();
+    for (qry, mut result) in batch.iter().zip(results) {
+        hist.push(result.took.as_micros() as u64);
+        // rescored results already carry exact scores
+        if args.real_scores && engine.forward.is_none() {
+            index.add_real_scores(&mut result, qry.weight_scale);
+        }
+        if let Some(log) = &mut query_log {
+            QueryLogRow::new(qry, &result).write(format, log)?;
+        }
+        // repeated queries of a cycled batch are evaluated once
+        if evaluated.insert(qry.id) {
+            let eval = evaluators
+                .qrels
+                .as_ref()
+                .and_then(|qrels| qrels.evaluate(&result, index.docmap(), eval_depth));
+            if let Some(eval) = eval {
+                if let Some(output) = &mut eval_output {
+                    match format {
+                        LogFormat::Csv => writeln!(
+                            output,
+                            "{},{},{},{},{},{}",
+                            eval.qid, eval.ndcg, eval.ap, eval.rr, eval.recall, eval.precision
+                        )?,
+                        LogFormat::Jsonl => {
+                            serde_json::to_writer(&mut *output, &eval)?;
+                            writeln!(output)?;
+                        }
+                    }
+                }
+                evaluations.push(eval);
+            }
+            let reference = evaluators
+                .reference
+                .as_ref()
+                .and_then(|reference| reference.get(qry.id));
+            if let Some(reference) = reference {
+                let ranking = ioqp::eval::ranking(&result, index.docmap());
+                let approx =
+                    ioqp::eval::Approximation::compare(qry.id, &ranking, reference, k, args.rbo_p);
+                if let Some(output) = &mut approx_output {
+                    match format {
+                        LogFormat::Csv => writeln!(
+                            output,
+                            "{},{},{},{}",
+                            approx.qid, approx.overlap, approx.rbo, approx.mass_recall
+                        )?,
+                        LogFormat::Jsonl => {
+                            serde_json::to_writer(&mut *output, &approx)?;
+                            writeln!(output)?;
+                        }
+                    }
+                }
+                approximations.push(approx);
+            }
+        }
+        result.to_trec_file(index.docmap(), &out_handle);
+    }
+
+    for output in [query_log, eval_output, approx_output].iter_mut().flatten() {
+        output.flush()?;
+    }
+    hist.sort_unstable();
+    Ok(Summary {
+        mode,
+        k,
+        hist,
+        elapsed,
+        eval: evaluators
+            .qrels
+            .as_ref()
+            .map(|_| (ioqp::eval::Evaluation::mean(&evaluations), eval_depth)),
+        approx: evaluators
+            .reference
+            .as_ref()
+            .map(|_| ioqp::eval::Approximation::mean(&approximations)),
+    })
+}
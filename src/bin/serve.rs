@@ -5,6 +5,7 @@ use axum::{
     routing::{get, post},
     Json, Router,
 };
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
 use std::sync::Arc;
 use structopt::StructOpt;
@@ -25,23 +26,143 @@ struct Args {
     /// Max blocking threads
     #[structopt(long, default_value = "8")]
     max_blocking_threads: u16,
+    /// Static document priors that break score ties, one `<docid> <prior>` per line
+    #[structopt(long, parse(from_os_str))]
+    priors: Option<std::path::PathBuf>,
+    /// Further indexes of the same documents for hybrid queries, as `<name>=<path>`
+    #[structopt(long)]
+    hybrid_index: Vec<NamedIndex>,
+    /// Accept deletions via `POST /admin/delete`, which is unauthenticated
+    #[structopt(long)]
+    enable_admin: bool,
+}
+
+/// Name under which hybrid queries refer to the index given with `--index`
+const MAIN_INDEX: &str = "main";
+
+#[derive(Debug)]
+struct NamedIndex {
+    name: String,
+    path: std::path::PathBuf,
+}
+
+impl std::str::FromStr for NamedIndex {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, path)) if !name.is_empty() && name != MAIN_INDEX => Ok(NamedIndex {
+                name: name.to_string(),
+                path: path.into(),
+            }),
+            _ => Err(anyhow::anyhow!(
+                "hybrid index must be <name>=<path> with a name other than {}",
+                MAIN_INDEX
+            )),
+        }
+    }
+}
+
+/// Documents to restrict the results to, or to remove from them
+#[derive(serde::Deserialize)]
+enum FilterSpec {
+    #[serde(rename = "AllowDocIds")]
+    Allow(Vec<u32>),
+    #[serde(rename = "DenyDocIds")]
+    Deny(Vec<u32>),
+    #[serde(rename = "AllowCollectionDocIds")]
+    AllowCollection(Vec<String>),
+    #[serde(rename = "DenyCollectionDocIds")]
+    DenyCollection(Vec<String>),
+}
+
+impl FilterSpec {
+    /// Builds the filter for `index`, dropping docids outside of it
+    fn resolve(&self, index: &IndexType) -> ioqp::DocFilter {
+        let num_docs = index.max_doc_id() + 1;
+        match self {
+            FilterSpec::Allow(doc_ids) => ioqp::DocFilter::allow_doc_ids(doc_ids, num_docs),
+            FilterSpec::Deny(doc_ids) => ioqp::DocFilter::deny_doc_ids(doc_ids, num_docs),
+            FilterSpec::AllowCollection(names) => {
+                ioqp::DocFilter::allow_doc_ids(&index.resolve_doc_ids(names), num_docs)
+            }
+            FilterSpec::DenyCollection(names) => {
+                ioqp::DocFilter::deny_doc_ids(&index.resolve_doc_ids(names), num_docs)
+            }
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
-enum QueryMode {
-    Fraction(f32),
-    Fixed(i64),
+struct DeletePayLoad {
+    collection_doc_ids: Vec<String>,
+}
+
+#[derive(serde::Serialize)]
+struct DeleteResponse {
+    deleted: usize,
+    total_deleted: usize,
 }
 
 #[derive(serde::Deserialize)]
 struct QueryPayLoad {
     query: ioqp::query::Query,
     k: NonZeroUsize,
-    query_mode: QueryMode,
+    query_mode: ioqp::QueryMode,
+    #[serde(default)]
+    filter: Option<FilterSpec>,
+    /// Number of document ranges to process the query on in parallel
+    #[serde(default)]
+    parallelism: Option<NonZeroUsize>,
+    /// Whether results carry approximate original scores
+    #[serde(default)]
+    real_scores: bool,
+}
+
+/// The query for one index of a hybrid query and the weight of its scores
+#[derive(serde::Deserialize)]
+struct HybridPart {
+    index: String,
+    query: ioqp::query::Query,
+    #[serde(default = "unit_weight")]
+    weight: f32,
+}
+
+fn unit_weight() -> f32 {
+    1.0
+}
+
+/// A query on several indexes of the same documents
+#[derive(serde::Deserialize)]
+struct HybridPayLoad {
+    queries: Vec<HybridPart>,
+    k: NonZeroUsize,
+    query_mode: ioqp::QueryMode,
+    #[serde(default)]
+    filter: Option<FilterSpec>,
+    /// Fuse the rankings of the indexes with this reciprocal rank fusion constant instead of
+    /// adding their weighted scores
+    #[serde(default)]
+    rrf_k: Option<f64>,
+}
+
+/// Document to explain the score of for a query, by internal or collection docid
+#[derive(serde::Deserialize)]
+struct ExplainPayLoad {
+    query: ioqp::query::Query,
+    k: NonZeroUsize,
+    query_mode: ioqp::QueryMode,
+    #[serde(default)]
+    doc_id: Option<u32>,
+    #[serde(default)]
+    collection_doc_id: Option<String>,
 }
 
 enum ServeError {
     JoinWorkerError,
+    PersistDeletionsError,
+    UnknownDocument,
+    UnknownIndex,
+    InvalidHybridQuery,
 }
 
 impl IntoResponse for ServeError {
@@ -51,6 +172,13 @@ impl IntoResponse for ServeError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Join worker thread error",
             ),
+            ServeError::PersistDeletionsError => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Could not persist deleted documents",
+            ),
+            ServeError::UnknownDocument => (StatusCode::BAD_REQUEST, "Unknown document"),
+            ServeError::UnknownIndex => (StatusCode::BAD_REQUEST, "Unknown index"),
+            ServeError::InvalidHybridQuery => (StatusCode::BAD_REQUEST, "Invalid hybrid query"),
         };
         let body = Json(serde_json::json!({
             "error": error_message,
@@ -60,7 +188,6 @@ impl IntoResponse for ServeError {
 }
 type IndexType = ioqp::Index<ioqp::SimdBPandStreamVbyte>;
 
-
 // #[tokio::main(flavor = "current_thread")]
 // async fn main() -> anyhow::Result<()> {
 fn main() -> anyhow::Result<()> {
@@ -69,9 +196,33 @@ fn main() -> anyhow::Result<()> {
     info!("args = {:?}", &args);
 
     info!("loading index from file {}", args.index.display());
-    let index = IndexType::read_from_file(args.index)?;
+    let mut index = IndexType::read_from_file(&args.index)?;
+    if let Some(priors) = &args.priors {
+        info!("loading priors from file {}", priors.display());
+        index.read_priors(priors)?;
+    }
     let index = Arc::new(index);
-    let app = Router::new()
+    let mut indexes = HashMap::new();
+    indexes.insert(MAIN_INDEX.to_string(), Arc::clone(&index));
+    for hybrid_index in &args.hybrid_index {
+        info!(
+            "loading hybrid index {} from file {}",
+            hybrid_index.name,
+            hybrid_index.path.display()
+        );
+        let other = IndexType::read_from_file(&hybrid_index.path)?;
+        if other.docmap() != index.docmap() {
+            anyhow::bail!(
+                "hybrid index {} does not have the documents of the main index",
+                hybrid_index.name
+            );
+        }
+        indexes.insert(hybrid_index.name.clone(), Arc::new(other));
+    }
+    let indexes = Arc::new(indexes);
+    // deletions are persisted by one request at a time
+    let index_path = Arc::new(parking_lot::Mutex::new(args.index.clone()));
+    let mut app = Router::new()
         .route(
             "/search",
             post({
@@ -85,7 +236,31 @@ fn main() -> anyhow::Result<()> {
                 let index = Arc::clone(&index);
                 move |path| search(path, Arc::clone(&index))
             }),
+        )
+        .route(
+            "/explain",
+            post({
+                let index = Arc::clone(&index);
+                move |body| explain_post(body, Arc::clone(&index))
+            }),
+        )
+        .route(
+            "/hybrid",
+            post({
+                let indexes = Arc::clone(&indexes);
+                move |body| hybrid_post(body, Arc::clone(&indexes))
+            }),
+        );
+    if args.enable_admin {
+        info!("enable admin endpoints, they are reachable by anyone who can reach the server");
+        app = app.route(
+            "/admin/delete",
+            post({
+                let index = Arc::clone(&index);
+                move |body| delete_post(body, Arc::clone(&index), Arc::clone(&index_path))
+            }),
         );
+    }
 
     let addr = format!("0.0.0.0:{}", args.port).parse()?;
     info!("start http endpoint at {}", &addr);
@@ -109,20 +284,29 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+fn process_query(query: QueryPayLoad, index: &IndexType) -> ioqp::Results {
+    let filter = query.filter.as_ref().map(|spec| spec.resolve(index));
+    let mut results = index.query_parallel(
+        &query.query.tokens,
+        query.query_mode,
+        None,
+        query.k.get(),
+        filter.as_ref(),
+        query.parallelism.map_or(1, NonZeroUsize::get),
+    );
+    if query.real_scores {
+        index.add_real_scores(&mut results, query.query.weight_scale);
+    }
+    results
+}
+
 async fn search_post(
     Json(query): Json<QueryPayLoad>,
     index: Arc<IndexType>,
 ) -> Result<Json<ioqp::Results>, ServeError> {
-    let result = tokio::task::spawn_blocking(move || match query.query_mode {
-        QueryMode::Fraction(rho) => {
-            index.query_fraction(&query.query.tokens, rho, None, query.k.get())
-        }
-        QueryMode::Fixed(postings_budget) => {
-            index.query_fixed(&query.query.tokens, postings_budget, None, query.k.get())
-        }
-    })
-    .await
-    .map_err(|_| ServeError::JoinWorkerError)?;
+    let result = tokio::task::spawn_blocking(move || process_query(query, &index))
+        .await
+        .map_err(|_| ServeError::JoinWorkerError)?;
 
     Ok(Json(result))
 }
@@ -132,16 +316,105 @@ async fn search(
     index: Arc<IndexType>,
 ) -> Result<Json<ioqp::Results>, ServeError> {
     let query: QueryPayLoad = query.0;
-    let result = tokio::task::spawn_blocking(move || match query.query_mode {
-        QueryMode::Fraction(rho) => {
-            index.query_fraction(&query.query.tokens, rho, None, query.k.get())
-        }
-        QueryMode::Fixed(postings_budget) => {
-            index.query_fixed(&query.query.tokens, postings_budget, None, query.k.get())
-        }
+    let result = tokio::task::spawn_blocking(move || process_query(query, &index))
+        .await
+        .map_err(|_| ServeError::JoinWorkerError)?;
+
+    Ok(Json(result))
+}
+
+async fn explain_post(
+    Json(payload): Json<ExplainPayLoad>,
+    index: Arc<IndexType>,
+) -> Result<Json<ioqp::Explanation>, ServeError> {
+    let explanation = tokio::task::spawn_blocking(move || {
+        let doc_id = match (payload.doc_id, &payload.collection_doc_id) {
+            (Some(doc_id), _) => doc_id,
+            (None, Some(name)) => index
+                .resolve_doc_ids(std::slice::from_ref(name))
+                .pop()
+                .ok_or(ServeError::UnknownDocument)?,
+            (None, None) => return Err(ServeError::UnknownDocument),
+        };
+        index
+            .explain(
+                &payload.query.tokens,
+                payload.query_mode,
+                payload.k.get(),
+                doc_id,
+            )
+            .map_err(|_| ServeError::UnknownDocument)
     })
     .await
-    .map_err(|_| ServeError::JoinWorkerError)?;
+    .map_err(|_| ServeError::JoinWorkerError)??;
+
+    Ok(Json(explanation))
+}
+
+fn process_hybrid_query(
+    payload: HybridPayLoad,
+    indexes: &HashMap<String, Arc<IndexType>>,
+) -> Result<ioqp::Results, ServeError> {
+    let main = &indexes[MAIN_INDEX];
+    let filter = payload.filter.as_ref().map(|spec| spec.resolve(main));
+    let parts = payload
+        .queries
+        .iter()
+        .map(|part| {
+            Ok(ioqp::HybridQuery {
+                index: indexes.get(&part.index).ok_or(ServeError::UnknownIndex)?,
+                tokens: &part.query.tokens,
+                weight: part.weight,
+            })
+        })
+        .collect::<Result<Vec<_>, ServeError>>()?;
+    let k = payload.k.get();
+    let results = match payload.rrf_k {
+        Some(rrf_k) => ioqp::Index::query_hybrid_fused(
+            &parts,
+            ioqp::fusion::Fusion::Rrf { k: rrf_k },
+            payload.query_mode,
+            None,
+            k,
+            filter.as_ref(),
+        ),
+        None => ioqp::Index::query_hybrid(&parts, payload.query_mode, None, k, filter.as_ref()),
+    };
+    results.map_err(|_| ServeError::InvalidHybridQuery)
+}
+
+async fn hybrid_post(
+    Json(payload): Json<HybridPayLoad>,
+    indexes: Arc<HashMap<String, Arc<IndexType>>>,
+) -> Result<Json<ioqp::Results>, ServeError> {
+    let result = tokio::task::spawn_blocking(move || process_hybrid_query(payload, &indexes))
+        .await
+        .map_err(|_| ServeError::JoinWorkerError)??;
 
     Ok(Json(result))
 }
+
+async fn delete_post(
+    Json(payload): Json<DeletePayLoad>,
+    index: Arc<IndexType>,
+    index_path: Arc<parking_lot::Mutex<std::path::PathBuf>>,
+) -> Result<Json<DeleteResponse>, ServeError> {
+    let response = tokio::task::spawn_blocking(move || {
+        let deleted = index.delete_collection_doc_ids(&payload.collection_doc_ids);
+        info!("deleted {} docs", deleted);
+        index
+            .write_deletions(&*index_path.lock())
+            .map(|_| DeleteResponse {
+                deleted,
+                total_deleted: index.num_deleted(),
+            })
+    })
+    .await
+    .map_err(|_| ServeError::JoinWorkerError)?
+    .map_err(|err| {
+        tracing::error!("could not persist deletions: {}", err);
+        ServeError::PersistDeletionsError
+    })?;
+
+    Ok(Json(response))
+}
@@ -17,7 +17,7 @@ pub fn do_work() -> i32 {
     for _ in 0..10000000 {
         x = 2 * x + 4 - 3 * x + 2 - 5 * x + 5;
     }
-    x as i32
+    x
 }
 
 fn main() -> anyhow::Result<()> {
@@ -0,0 +1,119 @@
+const WORD_BITS: usize = u64::BITS as usize;
+const WORD_SHIFT: usize = WORD_BITS.ilog2() as usize;
+
+/// A set of document identifiers stored as one bit per document
+#[derive(Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize, Debug)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    /// Creates an empty bitmap able to hold `num_docs` identifiers without growing
+    #[must_use]
+    pub fn with_capacity(num_docs: usize) -> Self {
+        Self {
+            words: vec![0; (num_docs >> WORD_SHIFT) + 1],
+        }
+    }
+
+    /// Creates a bitmap from a list of document identifiers of a collection of `num_docs`
+    /// documents
+    ///
+    /// Identifiers outside the collection are dropped, so the bitmap never grows past it.
+    #[must_use]
+    pub fn from_doc_ids(doc_ids: &[u32], num_docs: usize) -> Self {
+        let mut bitmap = Self::with_capacity(num_docs);
+        doc_ids
+            .iter()
+            .filter(|&&doc_id| (doc_id as usize) < num_docs)
+            .for_each(|&doc_id| bitmap.insert(doc_id));
+        bitmap
+    }
+
+    pub fn insert(&mut self, doc_id: u32) {
+        let word = doc_id as usize >> WORD_SHIFT;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (doc_id as usize & (WORD_BITS - 1));
+    }
+
+    pub fn remove(&mut self, doc_id: u32) {
+        if let Some(word) = self.words.get_mut(doc_id as usize >> WORD_SHIFT) {
+            *word &= !(1 << (doc_id as usize & (WORD_BITS - 1)));
+        }
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn contains(&self, doc_id: u32) -> bool {
+        match self.words.get(doc_id as usize >> WORD_SHIFT) {
+            Some(word) => word & (1 << (doc_id as usize & (WORD_BITS - 1))) != 0,
+            None => false,
+        }
+    }
+
+    /// Removes all identifiers while keeping the allocated capacity
+    pub fn clear(&mut self) {
+        self.words.iter_mut().for_each(|w| *w = 0);
+    }
+
+    /// Number of identifiers in the set
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&w| w == 0)
+    }
+
+    /// Iterates the identifiers in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        self.words.iter().enumerate().flat_map(|(idx, &word)| {
+            let base = (idx << WORD_SHIFT) as u32;
+            (0..WORD_BITS as u32)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| base + bit)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[quickcheck_macros::quickcheck]
+    fn contains_exactly_inserted(doc_ids: Vec<u16>) -> bool {
+        let doc_ids: Vec<u32> = doc_ids.into_iter().map(u32::from).collect();
+        let bitmap = Bitmap::from_doc_ids(&doc_ids, 1 << 16);
+        let mut expected = doc_ids.clone();
+        expected.sort_unstable();
+        expected.dedup();
+        bitmap.len() == expected.len() && bitmap.iter().eq(expected.into_iter())
+    }
+
+    #[test]
+    fn remove_and_clear() {
+        let mut bitmap = Bitmap::with_capacity(10);
+        bitmap.insert(3);
+        bitmap.insert(130);
+        assert!(bitmap.contains(3));
+        assert!(bitmap.contains(130));
+        assert!(!bitmap.contains(4));
+        assert!(!bitmap.contains(100_000));
+        bitmap.remove(3);
+        assert!(!bitmap.contains(3));
+        assert_eq!(bitmap.len(), 1);
+        bitmap.clear();
+        assert!(bitmap.is_empty());
+    }
+
+    #[test]
+    fn doc_ids_outside_the_collection_are_dropped() {
+        let bitmap = Bitmap::from_doc_ids(&[1, 9, u32::MAX], 10);
+        assert_eq!(bitmap.iter().collect::<Vec<_>>(), vec![1, 9]);
+        assert_eq!(bitmap, Bitmap::from_doc_ids(&[1, 9], 10));
+    }
+}
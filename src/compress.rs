@@ -57,24 +57,24 @@ impl Compressor for Uncompressed {
         for val in input {
             output.write_u32::<LittleEndian>(*val).unwrap();
         }
-        input.len() * std::mem::size_of::<u32>()
+        std::mem::size_of_val(input)
     }
     fn compress_sorted(_initial: u32, input: &[u32], mut output: &mut [u8]) -> usize {
         for val in input {
             output.write_u32::<LittleEndian>(*val).unwrap();
         }
-        input.len() * std::mem::size_of::<u32>()
+        std::mem::size_of_val(input)
     }
     fn decompress_sorted_full(_initial: u32, mut input: &[u8], output: &mut [u32]) -> usize {
         for out in output.iter_mut() {
             *out = input.read_u32::<LittleEndian>().unwrap();
         }
-        output.len() * std::mem::size_of::<u32>()
+        std::mem::size_of_val(output)
     }
     fn decompress_sorted(_initial: u32, mut input: &[u8], output: &mut [u32]) -> usize {
         for out in output.iter_mut() {
             *out = input.read_u32::<LittleEndian>().unwrap();
         }
-        output.len() * std::mem::size_of::<u32>()
+        std::mem::size_of_val(output)
     }
 }
@@ -0,0 +1,206 @@
+/// Latency model of query processing, calibrated per index with the `calibrate` tool
+///
+/// The latency of a query is modelled as a fixed cost plus the cost of decoding and accumulating
+/// each impact group and each posting it processes. The costs are fitted to queries on the index
+/// rather than taken from the decode benchmarks, which time decoding alone: accumulating into
+/// collection-sized arrays and selecting the top-k depend on the index and the machine.
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
+pub struct CostModel {
+    /// Fixed cost of a query, e.g. initializing accumulators and selecting the top-k
+    pub base_micros: f64,
+    pub micros_per_group: f64,
+    pub micros_per_posting: f64,
+}
+
+/// Measured costs of a query that processed `groups` impact groups with `postings` postings
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub groups: usize,
+    pub postings: usize,
+    /// Time spent decoding and accumulating the processed impact groups
+    pub accumulation_micros: f64,
+    /// Time spent on the rest of the query, e.g. planning and selecting the top-k
+    pub overhead_micros: f64,
+}
+
+impl CostModel {
+    /// Fits the costs per group and posting to the accumulation times of the samples with least
+    /// squares, and the fixed cost to the rest of their latencies
+    ///
+    /// # Errors
+    /// - the samples don't determine the model, e.g. all queries processed the same postings
+    /// - processing postings does not appear to cost time
+    pub fn fit(samples: &[Sample]) -> anyhow::Result<CostModel> {
+        // normal equations of accumulation = intercept + groups * a + postings * b
+        let mut lhs = [[0_f64; 3]; 3];
+        let mut rhs = [0_f64; 3];
+        for sample in samples {
+            let x = [1.0, sample.groups as f64, sample.postings as f64];
+            for row in 0..3 {
+                for col in 0..3 {
+                    lhs[row][col] += x[row] * x[col];
+                }
+                rhs[row] += x[row] * sample.accumulation_micros;
+            }
+        }
+        let [intercept, micros_per_group, micros_per_posting] = solve(lhs, rhs)
+            .ok_or_else(|| anyhow::anyhow!("Samples do not vary enough to fit a cost model"))?;
+        if micros_per_posting <= 0.0 {
+            anyhow::bail!("Fitted cost per posting is not positive. Use more or longer queries");
+        }
+        let overhead_micros = samples
+            .iter()
+            .fold(0.0, |sum, sample| sum + sample.overhead_micros)
+            / samples.len() as f64;
+        Ok(CostModel {
+            base_micros: intercept + overhead_micros,
+            micros_per_group: micros_per_group.max(0.0),
+            micros_per_posting,
+        })
+    }
+
+    #[must_use]
+    pub fn estimate(&self, groups: usize, postings: usize) -> std::time::Duration {
+        let micros = self.base_micros
+            + self.micros_per_group * groups as f64
+            + self.micros_per_posting * postings as f64;
+        std::time::Duration::from_secs_f64(micros.max(0.0) / 1_000_000.0)
+    }
+
+    /// The largest postings budget whose estimated latency fits into `target`
+    ///
+    /// `group_counts` are the postings of the impact groups left to process, in processing order.
+    #[must_use]
+    pub fn budget(&self, group_counts: &[u32], target: std::time::Duration) -> i64 {
+        // a group is processed as long as the budget before it is not negative
+        let mut budget = -1;
+        let mut postings = 0;
+        for (groups, &count) in group_counts.iter().enumerate() {
+            postings += count as usize;
+            if self.estimate(groups + 1, postings) > target {
+                return budget;
+            }
+            budget = postings as i64 - 1;
+        }
+        postings as i64
+    }
+
+    /// Reads a model written by `write_to_file`
+    ///
+    /// # Errors
+    /// - fails if file can't be opened or parsed
+    pub fn read_from_file<P: AsRef<std::path::Path>>(file_name: P) -> anyhow::Result<CostModel> {
+        let input_file = std::io::BufReader::new(std::fs::File::open(file_name)?);
+        Ok(serde_json::from_reader(input_file)?)
+    }
+
+    /// Writes the model as JSON
+    ///
+    /// # Errors
+    /// - fails if file can't be created
+    pub fn write_to_file<P: AsRef<std::path::Path>>(&self, file_name: P) -> anyhow::Result<()> {
+        let output_file = std::io::BufWriter::new(std::fs::File::create(file_name)?);
+        serde_json::to_writer_pretty(output_file, self)?;
+        Ok(())
+    }
+}
+
+/// Number of impact groups and postings processed with a postings budget
+#[must_use]
+pub fn processed(group_counts: &[u32], mut postings_budget: i64) -> (usize, usize) {
+    let mut groups = 0;
+    let mut postings = 0;
+    for &count in group_counts {
+        if postings_budget < 0 {
+            break;
+        }
+        groups += 1;
+        postings += count as usize;
+        postings_budget -= i64::from(count);
+    }
+    (groups, postings)
+}
+
+/// The cost model of an index is stored next to it with an extra extension
+pub fn sidecar_path<P: AsRef<std::path::Path>>(index_file_name: P) -> std::path::PathBuf {
+    let mut file_name = index_file_name.as_ref().as_os_str().to_owned();
+    file_name.push(".cost");
+    std::path::PathBuf::from(file_name)
+}
+
+/// Solves the 3x3 linear system with gaussian elimination
+fn solve(mut lhs: [[f64; 3]; 3], mut rhs: [f64; 3]) -> Option<[f64; 3]> {
+    for col in 0..3 {
+        let pivot = (col..3).max_by(|&a, &b| lhs[a][col].abs().total_cmp(&lhs[b][col].abs()))?;
+        if lhs[pivot][col].abs() < 1e-9 {
+            return None;
+        }
+        lhs.swap(col, pivot);
+        rhs.swap(col, pivot);
+        for row in col + 1..3 {
+            let pivot_row = lhs[col];
+            let factor = lhs[row][col] / pivot_row[col];
+            for (value, pivot_value) in lhs[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot_value;
+            }
+            rhs[row] -= factor * rhs[col];
+        }
+    }
+    let mut solution = [0_f64; 3];
+    for row in (0..3).rev() {
+        let known: f64 = (row + 1..3).map(|k| lhs[row][k] * solution[k]).sum();
+        solution[row] = (rhs[row] - known) / lhs[row][row];
+    }
+    Some(solution)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_recovers_linear_costs() {
+        let samples: Vec<Sample> = (1..20)
+            .flat_map(|groups| {
+                (1..5).map(move |step| {
+                    let postings = groups * 100 + step * 7919;
+                    Sample {
+                        groups,
+                        postings,
+                        accumulation_micros: 5.0 + 0.5 * groups as f64 + 0.01 * postings as f64,
+                        // averages 15 over the samples
+                        overhead_micros: 14.0 + (groups % 3) as f64,
+                    }
+                })
+            })
+            .collect();
+        let model = CostModel::fit(&samples).unwrap();
+        assert!((model.base_micros - 20.0).abs() < 1e-6);
+        assert!((model.micros_per_group - 0.5).abs() < 1e-6);
+        assert!((model.micros_per_posting - 0.01).abs() < 1e-6);
+
+        let constant = vec![samples[0]; 10];
+        assert!(CostModel::fit(&constant).is_err());
+    }
+
+    #[test]
+    fn budget_fits_target_latency() {
+        let model = CostModel {
+            base_micros: 10.0,
+            micros_per_group: 0.0,
+            micros_per_posting: 1.0,
+        };
+        let counts = [5, 10, 20];
+        let micros = std::time::Duration::from_micros;
+        assert_eq!(model.budget(&counts, micros(9)), -1);
+        assert_eq!(model.budget(&counts, micros(15)), 4);
+        assert_eq!(model.budget(&counts, micros(30)), 14);
+        assert_eq!(model.budget(&counts, micros(1000)), 35);
+        for (target, expected) in [(9, (0, 0)), (15, (1, 5)), (30, (2, 15)), (1000, (3, 35))] {
+            assert_eq!(
+                processed(&counts, model.budget(&counts, micros(target))),
+                expected
+            );
+        }
+    }
+}
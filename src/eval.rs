@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use crate::search::Results;
+
+/// Depth of the reciprocal rank
+pub const MRR_DEPTH: usize = 10;
+
+/// TREC relevance judgments, the relevance grade of each judged document per query
+#[derive(Default, Debug)]
+pub struct Qrels {
+    judgments: HashMap<usize, HashMap<String, i32>>,
+}
+
+impl Qrels {
+    /// Reads qrels in the TREC format `<qid> <iteration> <collection docid> <grade>`
+    pub fn read_from_file<P: AsRef<std::path::Path>>(file: P) -> anyhow::Result<Self> {
+        let reader = std::io::BufReader::new(std::fs::File::open(file)?);
+        let mut qrels = Self::default();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 4 {
+                anyhow::bail!("qrels line '{}' does not have four fields", line);
+            }
+            qrels.insert(fields[0].parse()?, fields[2], fields[3].parse()?);
+        }
+        Ok(qrels)
+    }
+
+    pub fn insert(&mut self, qid: usize, collection_doc_id: &str, grade: i32) {
+        self.judgments
+            .entry(qid)
+            .or_default()
+            .insert(collection_doc_id.to_string(), grade);
+    }
+
+    /// Relevance grade of a document, unjudged documents are not relevant
+    #[must_use]
+    pub fn grade(&self, qid: usize, collection_doc_id: &str) -> i32 {
+        self.judgments
+            .get(&qid)
+            .and_then(|judged| judged.get(collection_doc_id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Evaluates the ranking of a query at depth `k`
+    ///
+    /// Documents with a positive grade are relevant and grades are the gains of nDCG.
+    /// Returns `None` for queries without judgments, which `trec_eval` skips as well.
+    #[must_use]
+    pub fn evaluate(&self, results: &Results, docmap: &[String], k: usize) -> Option<Evaluation> {
+        let judged = self.judgments.get(&results.qid)?;
+        let gains: Vec<i32> = results
+            .topk
+            .iter()
+            .map(|res| {
+                let grade = judged.get(&docmap[res.doc_id as usize]);
+                grade.copied().unwrap_or_default().max(0)
+            })
+            .collect();
+        let mut ideal: Vec<i32> = judged.values().map(|&grade| grade.max(0)).collect();
+        ideal.sort_unstable_by(|a, b| b.cmp(a));
+        let num_relevant = ideal.iter().filter(|&&grade| grade > 0).count();
+
+        let dcg = |gains: &[i32]| -> f64 {
+            gains
+                .iter()
+                .take(k)
+                .enumerate()
+                .fold(0.0, |dcg, (rank, &gain)| {
+                    dcg + f64::from(gain) / (rank as f64 + 2.0).log2()
+                })
+        };
+        let ideal_dcg = dcg(&ideal);
+
+        let mut relevant_found = 0;
+        let mut precision_sum = 0.0;
+        for (rank, _) in gains.iter().enumerate().filter(|(_, &gain)| gain > 0) {
+            relevant_found += 1;
+            precision_sum += relevant_found as f64 / (rank + 1) as f64;
+        }
+        let relevant_at_k = gains.iter().take(k).filter(|&&gain| gain > 0).count();
+        let ratio = |num: f64, den: usize| if den == 0 { 0.0 } else { num / den as f64 };
+
+        Some(Evaluation {
+            qid: results.qid,
+            ndcg: if ideal_dcg > 0.0 {
+                dcg(&gains) / ideal_dcg
+            } else {
+                0.0
+            },
+            ap: ratio(precision_sum, num_relevant),
+            rr: gains
+                .iter()
+                .take(MRR_DEPTH)
+                .position(|&gain| gain > 0)
+                .map_or(0.0, |rank| 1.0 / (rank + 1) as f64),
+            recall: ratio(relevant_at_k as f64, num_relevant),
+            precision: ratio(relevant_at_k as f64, k),
+        })
+    }
+}
+
+/// Effectiveness of the ranking of one query
+///
+/// `ndcg`, `recall` and `precision` are cut off at the evaluation depth, `rr` at
+/// `MRR_DEPTH` and `ap` uses the whole ranking.
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
+pub struct Evaluation {
+    pub qid: usize,
+    pub ndcg: f64,
+    pub ap: f64,
+    pub rr: f64,
+    pub recall: f64,
+    pub precision: f64,
+}
+
+impl Evaluation {
+    /// Averages the per-query evaluations, e.g. MAP and MRR from `ap` and `rr`
+    ///
+    /// The `qid` of the mean is the number of queries averaged.
+    #[must_use]
+    pub fn mean(evaluations: &[Evaluation]) -> Evaluation {
+        let n = evaluations.len().max(1) as f64;
+        let mean = |metric: fn(&Evaluation) -> f64| {
+            evaluations.iter().map(metric).fold(0.0, |a, b| a + b) / n
+        };
+        Evaluation {
+            qid: evaluations.len(),
+            ndcg: mean(|e| e.ndcg),
+            ap: mean(|e| e.ap),
+            rr: mean(|e| e.rr),
+            recall: mean(|e| e.recall),
+            precision: mean(|e| e.precision),
+        }
+    }
+}
+
+/// A ranked list of collection docids and their scores, best first
+pub type Ranking = Vec<(String, f64)>;
+
+/// The ranking of the results, with approximate original scores if they were added
+#[must_use]
+pub fn ranking(results: &Results, docmap: &[String]) -> Ranking {
+    results
+        .topk
+        .iter()
+        .map(|res| {
+            let score = res.real_score.map_or(f64::from(res.score), f64::from);
+            (docmap[res.doc_id as usize].clone(), score)
+        })
+        .collect()
+}
+
+/// Rankings of a set of queries, e.g. a reference run of exhaustive processing
+#[derive(Default, Debug)]
+pub struct Run {
+    rankings: HashMap<usize, Ranking>,
+}
+
+impl Run {
+    /// Reads a TREC run file `<qid> Q0 <collection docid> <rank> <score> <tag>`
+    pub fn read_from_file<P: AsRef<std::path::Path>>(file: P) -> anyhow::Result<Self> {
+        let reader = std::io::BufReader::new(std::fs::File::open(file)?);
+        let mut ranked: HashMap<usize, Vec<(usize, String, f64)>> = HashMap::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 6 {
+                anyhow::bail!("run line '{}' does not have six fields", line);
+            }
+            ranked.entry(fields[0].parse()?).or_default().push((
+                fields[3].parse()?,
+                fields[2].to_string(),
+                fields[4].parse()?,
+            ));
+        }
+        let mut run = Self::default();
+        for (qid, mut entries) in ranked {
+            entries.sort_by_key(|(rank, _, _)| *rank);
+            let ranking = entries.into_iter().map(|(_, doc, score)| (doc, score));
+            run.insert(qid, ranking.collect());
+        }
+        Ok(run)
+    }
+
+    pub fn insert(&mut self, qid: usize, ranking: Ranking) {
+        self.rankings.insert(qid, ranking);
+    }
+
+    #[must_use]
+    pub fn get(&self, qid: usize) -> Option<&Ranking> {
+        self.rankings.get(&qid)
+    }
+
+    /// The queries of the run in ascending order
+    #[must_use]
+    pub fn qids(&self) -> Vec<usize> {
+        let mut qids: Vec<usize> = self.rankings.keys().copied().collect();
+        qids.sort_unstable();
+        qids
+    }
+
+    /// Writes the run in the TREC format, ordered by query
+    pub fn write_trec<W: std::io::Write>(&self, mut output: W, tag: &str) -> anyhow::Result<()> {
+        for qid in self.qids() {
+            for (rank, (doc, score)) in self.rankings[&qid].iter().enumerate() {
+                writeln!(output, "{} Q0 {} {} {} {}", qid, doc, rank + 1, score, tag)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How closely an approximate ranking of one query matches a reference ranking
+///
+/// All metrics compare the top-k of both rankings and are 1 for identical ones.
+#[derive(Clone, Copy, Default, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
+pub struct Approximation {
+    pub qid: usize,
+    /// Fraction of the reference top-k that was retrieved
+    pub overlap: f64,
+    /// Extrapolated rank-biased overlap with persistence `p`
+    pub rbo: f64,
+    /// Fraction of the total reference score of the reference top-k that was retrieved
+    pub mass_recall: f64,
+}
+
+impl Approximation {
+    /// Compares the top-`k` documents of `approximate` with those of `reference`
+    #[must_use]
+    pub fn compare(
+        qid: usize,
+        approximate: &[(String, f64)],
+        reference: &[(String, f64)],
+        k: usize,
+        p: f64,
+    ) -> Approximation {
+        let approximate = &approximate[..approximate.len().min(k)];
+        let reference = &reference[..reference.len().min(k)];
+        let retrieved: std::collections::HashSet<&str> =
+            approximate.iter().map(|(doc, _)| doc.as_str()).collect();
+        if reference.is_empty() {
+            let agree = if approximate.is_empty() { 1.0 } else { 0.0 };
+            return Approximation {
+                qid,
+                overlap: agree,
+                rbo: agree,
+                mass_recall: 1.0,
+            };
+        }
+
+        let found = || {
+            reference
+                .iter()
+                .filter(|(doc, _)| retrieved.contains(doc.as_str()))
+        };
+        let total_mass: f64 = reference.iter().map(|(_, score)| score).sum();
+        // folded from zero since an empty float sum is negative zero
+        let found_mass = found().fold(0.0, |mass, (_, score)| mass + score);
+
+        // agreement of the prefixes of each depth, see Webber et al. (2010)
+        let depth = approximate.len().max(reference.len());
+        let mut seen_approximate = std::collections::HashSet::new();
+        let mut seen_reference = std::collections::HashSet::new();
+        let mut overlap = 0usize;
+        let mut weighted_agreement = 0.0;
+        for d in 0..depth {
+            if let Some((doc, _)) = approximate.get(d) {
+                if seen_reference.contains(doc.as_str()) {
+                    overlap += 1;
+                }
+                seen_approximate.insert(doc.as_str());
+            }
+            if let Some((doc, _)) = reference.get(d) {
+                if seen_approximate.contains(doc.as_str()) {
+                    overlap += 1;
+                }
+                seen_reference.insert(doc.as_str());
+            }
+            weighted_agreement += p.powi(d as i32 + 1) * overlap as f64 / (d + 1) as f64;
+        }
+        let rbo = (1.0 - p) / p * weighted_agreement
+            + p.powi(depth as i32) * overlap as f64 / depth as f64;
+
+        Approximation {
+            qid,
+            overlap: found().count() as f64 / reference.len() as f64,
+            rbo,
+            mass_recall: if total_mass > 0.0 {
+                found_mass / total_mass
+            } else {
+                1.0
+            },
+        }
+    }
+
+    /// Averages the per-query approximations, the `qid` of the mean is the number of queries
+    #[must_use]
+    pub fn mean(approximations: &[Approximation]) -> Approximation {
+        let n = approximations.len().max(1) as f64;
+        let mean = |metric: fn(&Approximation) -> f64| {
+            approximations.iter().map(metric).fold(0.0, |a, b| a + b) / n
+        };
+        Approximation {
+            qid: approximations.len(),
+            overlap: mean(|a| a.overlap),
+            rbo: mean(|a| a.rbo),
+            mass_recall: mean(|a| a.mass_recall),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(qid: usize, doc_ids: &[u32]) -> Results {
+        Results {
+            topk: doc_ids
+                .iter()
+                .enumerate()
+                .map(|(rank, &doc_id)| crate::search::Result {
+                    doc_id,
+                    score: (100 - rank) as crate::ScoreType,
+                    tie: doc_id.into(),
+                    real_score: None,
+                })
+                .collect(),
+            took: std::time::Duration::default(),
+            qid,
+            exact: true,
+            matched: doc_ids.len(),
+            stats: crate::search::Stats::default(),
+        }
+    }
+
+    #[test]
+    fn metrics_match_hand_computed_values() {
+        let docmap: Vec<String> = (0..6).map(|d| format!("D{}", d)).collect();
+        let mut qrels = Qrels::default();
+        qrels.insert(1, "D1", 2);
+        qrels.insert(1, "D3", 1);
+        qrels.insert(1, "D5", 1);
+        qrels.insert(1, "D0", 0);
+
+        // relevant documents at ranks 2 and 4, D5 is not retrieved
+        let eval = qrels
+            .evaluate(&results(1, &[0, 1, 2, 3]), &docmap, 4)
+            .unwrap();
+        let dcg = 2.0 / 3f64.log2() + 1.0 / 5f64.log2();
+        let ideal_dcg = 2.0 + 1.0 / 3f64.log2() + 1.0 / 4f64.log2();
+        assert!((eval.ndcg - dcg / ideal_dcg).abs() < 1e-9);
+        assert!((eval.ap - (1.0 / 2.0 + 2.0 / 4.0) / 3.0).abs() < 1e-9);
+        assert!((eval.rr - 0.5).abs() < 1e-9);
+        assert!((eval.recall - 2.0 / 3.0).abs() < 1e-9);
+        assert!((eval.precision - 0.5).abs() < 1e-9);
+
+        // the depth cuts off nDCG, recall and precision but not AP
+        let eval = qrels
+            .evaluate(&results(1, &[0, 1, 2, 3]), &docmap, 2)
+            .unwrap();
+        assert!((eval.recall - 1.0 / 3.0).abs() < 1e-9);
+        assert!((eval.precision - 0.5).abs() < 1e-9);
+        assert!((eval.ap - (1.0 / 2.0 + 2.0 / 4.0) / 3.0).abs() < 1e-9);
+
+        // unjudged queries are not evaluated
+        assert_eq!(qrels.evaluate(&results(2, &[1]), &docmap, 4), None);
+
+        let mean = Evaluation::mean(&[
+            Evaluation {
+                rr: 1.0,
+                ..Default::default()
+            },
+            Evaluation {
+                rr: 0.5,
+                ..Default::default()
+            },
+        ]);
+        assert_eq!(mean.qid, 2);
+        assert!((mean.rr - 0.75).abs() < 1e-9);
+    }
+
+    #[test]
+    fn approximations_compare_with_the_reference_top_k() {
+        let ranking = |docs: &[(&str, f64)]| -> Ranking {
+            docs.iter()
+                .map(|&(doc, score)| (doc.to_string(), score))
+                .collect()
+        };
+        let reference = ranking(&[("a", 4.0), ("b", 3.0), ("c", 2.0), ("d", 1.0)]);
+
+        let same = Approximation::compare(1, &reference, &reference, 3, 0.9);
+        assert!((same.overlap - 1.0).abs() < 1e-9);
+        assert!((same.rbo - 1.0).abs() < 1e-9);
+        assert!((same.mass_recall - 1.0).abs() < 1e-9);
+
+        // c was missed and d only ranks below the depth of the reference
+        let approximate = ranking(&[("b", 3.0), ("a", 2.0), ("d", 1.0)]);
+        let approx = Approximation::compare(1, &approximate, &reference, 3, 0.5);
+        assert!((approx.overlap - 2.0 / 3.0).abs() < 1e-9);
+        assert!((approx.mass_recall - 7.0 / 9.0).abs() < 1e-9);
+        // agreements 0, 1 and 2/3 at depths 1 to 3
+        let rbo = (0.25 * 1.0 + 0.125 * 2.0 / 3.0) + 0.125 * 2.0 / 3.0;
+        assert!((approx.rbo - rbo).abs() < 1e-9);
+
+        let disjoint = Approximation::compare(1, &ranking(&[("x", 1.0)]), &reference, 3, 0.9);
+        assert_eq!(disjoint.overlap, 0.0);
+        assert_eq!(disjoint.rbo, 0.0);
+        assert_eq!(disjoint.mass_recall, 0.0);
+    }
+}
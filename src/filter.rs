@@ -0,0 +1,33 @@
+use crate::bitmap::Bitmap;
+
+/// Restricts the documents that can appear in the results of a query
+#[derive(Clone, serde::Serialize, serde::Deserialize, Debug)]
+pub enum DocFilter {
+    /// Only documents in the set are returned
+    Allow(Bitmap),
+    /// Documents in the set are never returned
+    Deny(Bitmap),
+}
+
+impl DocFilter {
+    /// Admits only the given documents of a collection of `num_docs` documents
+    #[must_use]
+    pub fn allow_doc_ids(doc_ids: &[u32], num_docs: usize) -> Self {
+        DocFilter::Allow(Bitmap::from_doc_ids(doc_ids, num_docs))
+    }
+
+    /// Admits all documents of a collection of `num_docs` documents except the given ones
+    #[must_use]
+    pub fn deny_doc_ids(doc_ids: &[u32], num_docs: usize) -> Self {
+        DocFilter::Deny(Bitmap::from_doc_ids(doc_ids, num_docs))
+    }
+
+    #[must_use]
+    #[inline]
+    pub fn admits(&self, doc_id: u32) -> bool {
+        match self {
+            DocFilter::Allow(docs) => docs.contains(doc_id),
+            DocFilter::Deny(docs) => !docs.contains(doc_id),
+        }
+    }
+}
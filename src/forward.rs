@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+
+use indicatif::ProgressIterator;
+
+use crate::ciff;
+use crate::query::{Term, TermKind};
+use crate::score::{IndexScorer, Scorer};
+use crate::search::Results;
+use crate::util;
+
+/// Forward index files start with these magic bytes and format version
+const FORWARD_HEADER: util::FileHeader = util::FileHeader {
+    kind: "forward index",
+    magic: b"IOQF",
+    version: 1,
+    create_with: "`create --forward-index`",
+};
+
+/// The terms and term frequencies of each document, for scoring candidates exactly
+///
+/// Documents are numbered as in the index built from the same CIFF file. Merging, appending or
+/// compacting the index renumbers them.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ForwardIndex {
+    scorer: IndexScorer,
+    // term identifier and document frequency of each term
+    terms: HashMap<String, (u32, u32)>,
+    // document length divided by the average document length
+    norm_doc_lens: Vec<f32>,
+    // the (term, frequency) pairs of document d, ordered by term, are entries
+    // doc_offsets[d]..doc_offsets[d + 1]
+    doc_offsets: Vec<usize>,
+    doc_terms: Vec<(u32, u32)>,
+}
+
+impl ForwardIndex {
+    /// Creates the forward index of a CIFF file for the scorer its index is built with
+    ///
+    /// # Errors
+    /// - Can't open ciff file
+    pub fn from_ciff_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        input_file_name: P,
+        scorer: IndexScorer,
+    ) -> anyhow::Result<Self> {
+        let ciff_reader = ciff::Reader::from_file(input_file_name)?;
+        let (_, doclen) = crate::index::read_doc_records(&ciff_reader)?;
+        let num_plists = ciff_reader.header.num_postings_lists as usize;
+
+        let mut terms = HashMap::with_capacity(num_plists);
+        let mut postings: Vec<Vec<(u32, u32)>> = vec![Vec::new(); doclen.len()];
+        let pb = util::progress_bar("invert postings", num_plists);
+        for idx in (0..num_plists).progress_with(pb) {
+            let plist = ciff_reader.postings_list(idx);
+            let term_id = idx as u32;
+            terms.insert(plist.term, (term_id, plist.postings.len() as u32));
+            let mut doc_id: usize = 0;
+            for ciff::Posting { docid, tf } in &plist.postings {
+                doc_id += *docid as usize;
+                postings[doc_id].push((term_id, *tf as u32));
+            }
+        }
+
+        let mut doc_offsets = Vec::with_capacity(postings.len() + 1);
+        let mut doc_terms = Vec::with_capacity(postings.iter().map(Vec::len).sum());
+        doc_offsets.push(0);
+        for doc_postings in postings {
+            // postings lists are read in term order
+            doc_terms.extend(doc_postings);
+            doc_offsets.push(doc_terms.len());
+        }
+        Ok(ForwardIndex {
+            scorer,
+            terms,
+            // scored with the same single precision lengths as the postings
+            norm_doc_lens: doclen.iter().map(|&len| len as f32).collect(),
+            doc_offsets,
+            doc_terms,
+        })
+    }
+
+    /// Write the forward index to file
+    ///
+    /// # Errors
+    /// - fails if file can't be created
+    /// - fails if the forward index can't be serialized
+    pub fn write_to_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        &self,
+        output_file_name: P,
+    ) -> anyhow::Result<()> {
+        let mut output_file = std::io::BufWriter::new(std::fs::File::create(output_file_name)?);
+        FORWARD_HEADER.write(&mut output_file)?;
+        bincode::serialize_into(output_file, &self)?;
+        Ok(())
+    }
+
+    /// Read a forward index from file
+    ///
+    /// # Errors
+    /// - fails if file does not exist
+    /// - fails if the file is not a forward index of the current format version
+    /// - fails if the forward index can't be deserialized
+    pub fn read_from_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        input_file_name: P,
+    ) -> anyhow::Result<Self> {
+        let mut input_file = std::io::BufReader::new(std::fs::File::open(&input_file_name)?);
+        FORWARD_HEADER.check(&mut input_file, &input_file_name)?;
+        Ok(bincode::deserialize_from(input_file)?)
+    }
+
+    #[must_use]
+    pub fn num_docs(&self) -> usize {
+        self.norm_doc_lens.len()
+    }
+
+    /// Unquantized score of a document for a query whose term weights were divided by
+    /// `weight_scale`
+    ///
+    /// Negative terms subtract their score, exclusion terms don't score.
+    #[must_use]
+    pub fn score(&self, tokens: &[Term], doc_id: u32, weight_scale: f32) -> f32 {
+        let doc_id = doc_id as usize;
+        let doc_terms = &self.doc_terms[self.doc_offsets[doc_id]..self.doc_offsets[doc_id + 1]];
+        let num_docs = self.num_docs() as u32;
+        // folded from zero since an empty float sum is negative zero
+        let score = tokens
+            .iter()
+            .filter_map(|tok| {
+                let &(term_id, doc_freq) = self.terms.get(&tok.token)?;
+                let at = doc_terms
+                    .binary_search_by_key(&term_id, |&(term, _)| term)
+                    .ok()?;
+                let tf = doc_terms[at].1;
+                let score = self
+                    .scorer
+                    .score(tf, doc_freq, self.norm_doc_lens[doc_id], num_docs)
+                    * tok.freq as f32;
+                match tok.kind {
+                    TermKind::Positive => Some(score),
+                    TermKind::Negative => Some(-score),
+                    TermKind::Exclude => None,
+                }
+            })
+            .fold(0.0, |score, term_score| score + term_score);
+        score * weight_scale
+    }
+
+    /// Scores the results of a query exactly and keeps the best `k` of them
+    ///
+    /// The exact scores become the `real_score` of the results. Documents with equal exact
+    /// scores keep their order.
+    pub fn rescore(&self, tokens: &[Term], results: &mut Results, k: usize, weight_scale: f32) {
+        for result in &mut results.topk {
+            result.real_score = Some(self.score(tokens, result.doc_id, weight_scale));
+        }
+        let exact = |result: &crate::search::Result| result.real_score.unwrap_or_default();
+        results.topk.sort_by(|a, b| exact(b).total_cmp(&exact(a)));
+        results.topk.truncate(k);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::{temp_path, write_ciff, TestIndex};
+    use crate::query::Query;
+    use crate::score::BM25;
+
+    #[test]
+    fn rescoring_ranks_candidates_by_exact_scores() {
+        let ciff = temp_path("rescore.ciff");
+        write_ciff(
+            &ciff,
+            "d",
+            40,
+            &[
+                ("rare", vec![(1, 3), (4, 5), (9, 4), (20, 1)]),
+                ("common", (0..30).map(|d| (d, 1 + d % 3)).collect()),
+            ],
+        );
+        let scorer = BM25::new(0.9, 0.4);
+        // two bits leave only four impacts
+        let index = TestIndex::from_ciff_file(&ciff, 2, scorer).unwrap();
+        let forward = ForwardIndex::from_ciff_file(&ciff, IndexScorer::BM25(scorer)).unwrap();
+        assert_eq!(forward.num_docs(), 40);
+        let forward_file = temp_path("rescore.fwd");
+        forward.write_to_file(&forward_file).unwrap();
+        let forward = ForwardIndex::read_from_file(&forward_file).unwrap();
+        assert_eq!(forward.num_docs(), 40);
+        // an index is not a forward index
+        index.write_to_file(&forward_file).unwrap();
+        let err = ForwardIndex::read_from_file(&forward_file).unwrap_err();
+        assert!(err.to_string().contains("not an ioqp forward index"));
+        std::fs::remove_file(forward_file).unwrap();
+
+        let query = "1:rare common".parse::<Query>().unwrap();
+        let mut results = index.query_fraction(&query.tokens, 1.0, None, 30);
+        forward.rescore(&query.tokens, &mut results, 4, 1.0);
+
+        let bm25 = |tf, df| scorer.score(tf, df, 1.0, 40);
+        let exact = |doc_id: u32| {
+            let rare = [(1, 3), (4, 5), (9, 4), (20, 1)]
+                .iter()
+                .find(|&&(d, _)| d == doc_id)
+                .map_or(0.0, |&(_, tf)| bm25(tf, 4));
+            let common = if doc_id < 30 {
+                bm25(1 + doc_id % 3, 30)
+            } else {
+                0.0
+            };
+            rare + common
+        };
+        let mut expected: Vec<u32> = (0..40).collect();
+        expected.sort_by(|&a, &b| exact(b).total_cmp(&exact(a)));
+        let ranking: Vec<u32> = results.topk.iter().map(|r| r.doc_id).collect();
+        assert_eq!(ranking, expected[..4]);
+        for result in &results.topk {
+            let real_score = result.real_score.unwrap();
+            assert!((real_score - exact(result.doc_id)).abs() < 1e-5);
+        }
+
+        // exclusion terms don't score, negative terms subtract
+        let query = "1:rare -common".parse::<Query>().unwrap();
+        assert!((forward.score(&query.tokens, 4, 1.0) - bm25(5, 4)).abs() < 1e-6);
+        let query = "1:rare ~common".parse::<Query>().unwrap();
+        let score = forward.score(&query.tokens, 4, 2.0);
+        assert!((score - (bm25(5, 4) - bm25(2, 30)) * 2.0).abs() < 1e-6);
+
+        std::fs::remove_file(ciff).unwrap();
+    }
+}
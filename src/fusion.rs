@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use crate::eval::Run;
+use crate::search::{self, Results};
+
+/// How scores of different runs are made comparable before they are combined
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Normalization {
+    /// Maps the scores of a ranking to [0, 1], constant rankings to 1
+    MinMax,
+    /// Subtracts the mean and divides by the standard deviation, constant rankings become 0
+    ZScore,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minmax" => Ok(Normalization::MinMax),
+            "zscore" => Ok(Normalization::ZScore),
+            _ => Err(anyhow::anyhow!("normalization must be minmax or zscore")),
+        }
+    }
+}
+
+impl Normalization {
+    fn apply<D>(self, ranking: &[(D, f64)]) -> Vec<f64> {
+        let scores = ranking.iter().map(|(_, score)| *score);
+        match self {
+            Normalization::MinMax => {
+                let min = scores.clone().fold(f64::INFINITY, f64::min);
+                let max = scores.clone().fold(f64::NEG_INFINITY, f64::max);
+                if max > min {
+                    scores.map(|score| (score - min) / (max - min)).collect()
+                } else {
+                    scores.map(|_| 1.0).collect()
+                }
+            }
+            Normalization::ZScore => {
+                let n = ranking.len() as f64;
+                let mean = scores.clone().fold(0.0, |sum, score| sum + score) / n;
+                let variance = scores
+                    .clone()
+                    .fold(0.0, |sum, score| sum + (score - mean).powi(2))
+                    / n;
+                let deviation = variance.sqrt();
+                if deviation > 0.0 {
+                    scores.map(|score| (score - mean) / deviation).collect()
+                } else {
+                    scores.map(|_| 0.0).collect()
+                }
+            }
+        }
+    }
+}
+
+/// How the rankings of the same query are combined
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Fusion {
+    /// Reciprocal rank fusion, documents score the sum of `1 / (k + rank)`
+    Rrf { k: f64 },
+    /// Documents score the sum of their normalized scores
+    CombSum(Normalization),
+    /// CombSUM times the number of rankings that retrieved the document
+    CombMnz(Normalization),
+}
+
+impl Fusion {
+    /// Fuses the rankings of one query into the top-`depth` documents
+    ///
+    /// Documents with equal fused scores are ordered by their docid.
+    #[must_use]
+    pub fn fuse<D>(self, rankings: &[&[(D, f64)]], depth: usize) -> Vec<(D, f64)>
+    where
+        D: Clone + Ord + std::hash::Hash,
+    {
+        self.fuse_weighted(rankings, &vec![1.0; rankings.len()], depth)
+    }
+
+    /// Fuses the rankings like `fuse`, with what each ranking adds to a document's score scaled
+    /// by its weight, e.g. weighted reciprocal rank fusion
+    #[must_use]
+    pub fn fuse_weighted<D>(
+        self,
+        rankings: &[&[(D, f64)]],
+        weights: &[f64],
+        depth: usize,
+    ) -> Vec<(D, f64)>
+    where
+        D: Clone + Ord + std::hash::Hash,
+    {
+        let mut fused: HashMap<&D, (f64, usize)> = HashMap::new();
+        for (ranking, &weight) in rankings.iter().zip(weights) {
+            let scores = match self {
+                Fusion::Rrf { k } => (1..=ranking.len())
+                    .map(|rank| 1.0 / (k + rank as f64))
+                    .collect(),
+                Fusion::CombSum(normalization) | Fusion::CombMnz(normalization) => {
+                    normalization.apply(ranking)
+                }
+            };
+            for ((doc, _), score) in ranking.iter().zip(scores) {
+                let entry = fused.entry(doc).or_default();
+                entry.0 += weight * score;
+                entry.1 += 1;
+            }
+        }
+
+        let mut ranking: Vec<(D, f64)> = fused
+            .into_iter()
+            .map(|(doc, (score, hits))| match self {
+                Fusion::CombMnz(_) => (doc.clone(), score * hits as f64),
+                _ => (doc.clone(), score),
+            })
+            .collect();
+        ranking.sort_by(|(a_doc, a), (b_doc, b)| b.total_cmp(a).then_with(|| a_doc.cmp(b_doc)));
+        ranking.truncate(depth);
+        ranking
+    }
+
+    /// Fuses the runs query by query, queries missing from a run are fused from the others
+    #[must_use]
+    pub fn fuse_runs(self, runs: &[Run], depth: usize) -> Run {
+        let mut qids: Vec<usize> = runs.iter().flat_map(Run::qids).collect();
+        qids.sort_unstable();
+        qids.dedup();
+        let mut fused = Run::default();
+        for qid in qids {
+            let rankings: Vec<&[(String, f64)]> = runs
+                .iter()
+                .filter_map(|run| run.get(qid).map(Vec::as_slice))
+                .collect();
+            fused.insert(qid, self.fuse(&rankings, depth));
+        }
+        fused
+    }
+
+    /// Fuses the results of a query on indexes with the same docids into its top-`k`, with the
+    /// results of each index weighted by the weight at the same position
+    ///
+    /// The fused scores are the `real_score` of the results, their quantized scores are zero.
+    /// `matched` counts the documents in any of the results.
+    #[must_use]
+    pub fn fuse_results(self, results: &[Results], weights: &[f64], k: usize) -> Results {
+        let rankings: Vec<Vec<(u32, f64)>> = results
+            .iter()
+            .map(|results| {
+                let score =
+                    |res: &search::Result| res.real_score.map_or(f64::from(res.score), f64::from);
+                results
+                    .topk
+                    .iter()
+                    .map(|res| (res.doc_id, score(res)))
+                    .collect()
+            })
+            .collect();
+        let rankings: Vec<&[(u32, f64)]> = rankings.iter().map(Vec::as_slice).collect();
+        let mut fused = self.fuse_weighted(&rankings, weights, usize::MAX);
+        let matched = fused.len();
+        fused.truncate(k);
+
+        let mut stats = search::Stats::default();
+        for results in results {
+            stats.total_postings += results.stats.total_postings;
+            stats.processed_postings += results.stats.processed_postings;
+            stats.processed_segments += results.stats.processed_segments;
+            stats.budget_exhausted |= results.stats.budget_exhausted;
+            stats.planning += results.stats.planning;
+            stats.accumulation += results.stats.accumulation;
+            stats.topk += results.stats.topk;
+        }
+        Results {
+            topk: fused
+                .into_iter()
+                .map(|(doc_id, score)| search::Result {
+                    doc_id,
+                    score: 0,
+                    tie: u64::from(doc_id),
+                    real_score: Some(score as f32),
+                })
+                .collect(),
+            took: results.iter().map(|results| results.took).sum(),
+            qid: results.first().map_or(0, |results| results.qid),
+            exact: results.iter().all(|results| results.exact),
+            matched,
+            stats,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::Ranking;
+
+    fn ranking(docs: &[(&str, f64)]) -> Ranking {
+        docs.iter()
+            .map(|&(doc, score)| (doc.to_string(), score))
+            .collect()
+    }
+
+    #[test]
+    fn fusion_combines_ranks_or_normalized_scores() {
+        let bm25 = ranking(&[("a", 12.0), ("b", 8.0), ("c", 4.0)]);
+        let dense = ranking(&[("c", 0.9), ("a", 0.7), ("d", 0.5)]);
+        let docs = |ranking: &Ranking| -> Vec<String> {
+            ranking.iter().map(|(doc, _)| doc.clone()).collect()
+        };
+
+        let rrf = Fusion::Rrf { k: 60.0 }.fuse(&[&bm25[..], &dense[..]], 10);
+        assert_eq!(docs(&rrf), ["a", "c", "b", "d"]);
+        assert!((rrf[0].1 - (1.0 / 61.0 + 1.0 / 62.0)).abs() < 1e-12);
+
+        // min-max scores are a: 1 + 0.5, c: 0 + 1, b: 0.5, d: 0
+        let sum = Fusion::CombSum(Normalization::MinMax).fuse(&[&bm25[..], &dense[..]], 3);
+        assert_eq!(docs(&sum), ["a", "c", "b"]);
+        assert!((sum[0].1 - 1.5).abs() < 1e-12);
+
+        // b only appears in one run, c and a in both
+        let mnz = Fusion::CombMnz(Normalization::MinMax).fuse(&[&bm25[..], &dense[..]], 10);
+        assert_eq!(docs(&mnz), ["a", "c", "b", "d"]);
+        assert!((mnz[1].1 - 2.0).abs() < 1e-12);
+
+        let z = Normalization::ZScore.apply(&bm25);
+        assert!((z.iter().sum::<f64>()).abs() < 1e-12);
+        assert!((z[0] - 1.5f64.sqrt()).abs() < 1e-12);
+
+        let mut first = Run::default();
+        first.insert(1, bm25.clone());
+        let mut second = Run::default();
+        second.insert(1, dense.clone());
+        second.insert(2, dense);
+        let fused = Fusion::Rrf { k: 60.0 }.fuse_runs(&[first, second], 2);
+        assert_eq!(fused.qids(), [1, 2]);
+        assert_eq!(docs(fused.get(1).unwrap()), ["a", "c"]);
+        assert_eq!(docs(fused.get(2).unwrap()), ["c", "a"]);
+    }
+
+    #[test]
+    fn fused_results_carry_fused_scores() {
+        let results = |doc_ids: &[u32]| Results {
+            topk: doc_ids
+                .iter()
+                .map(|&doc_id| search::Result {
+                    doc_id,
+                    score: 1,
+                    tie: 0,
+                    real_score: None,
+                })
+                .collect(),
+            took: std::time::Duration::from_millis(1),
+            qid: 7,
+            exact: true,
+            matched: doc_ids.len(),
+            stats: search::Stats::default(),
+        };
+        let both_results = [results(&[4, 2]), results(&[2, 9])];
+        let fused = Fusion::Rrf { k: 60.0 }.fuse_results(&both_results, &[1.0, 1.0], 2);
+        let topk: Vec<_> = fused
+            .topk
+            .iter()
+            .map(|r| (r.doc_id, r.real_score))
+            .collect();
+        let both = (1.0 / 62.0 + 1.0 / 61.0) as f32;
+        assert_eq!(topk, [(2, Some(both)), (4, Some((1.0 / 61.0) as f32))]);
+        assert_eq!(fused.matched, 3);
+        assert_eq!(fused.qid, 7);
+        assert_eq!(fused.took, std::time::Duration::from_millis(2));
+
+        // the ranks of results without weight add nothing
+        let fused = Fusion::Rrf { k: 60.0 }.fuse_results(&both_results, &[1.0, 0.0], 3);
+        let topk: Vec<_> = fused.topk.iter().map(|r| r.doc_id).collect();
+        assert_eq!(topk, [4, 2, 9]);
+    }
+}
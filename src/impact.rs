@@ -7,12 +7,17 @@ pub struct MetaData {
     pub bytes: u32,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Impact {
     pub meta_data: MetaData,
     pub remaining_u32s: usize,
     pub bytes: range::Byte,
     pub initial: u32,
+    // which index segment the bytes belong to and its first document identifier
+    pub segment: usize,
+    pub doc_offset: u32,
+    // position of the query term the impact segment belongs to
+    pub term: usize,
 }
 
 impl Impact {
@@ -33,6 +38,9 @@ impl Impact {
             meta_data,
             initial: 0,
             bytes,
+            segment: 0,
+            doc_offset: 0,
+            term: 0,
         }
     }
 
@@ -51,9 +59,27 @@ impl Impact {
             },
             initial: 0,
             bytes,
+            segment: 0,
+            doc_offset: 0,
+            term: 0,
         }
     }
 
+    /// Places the impact segment in index segment `segment` whose documents start at `doc_offset`
+    #[must_use]
+    pub fn in_segment(mut self, segment: usize, doc_offset: u32) -> Impact {
+        self.segment = segment;
+        self.doc_offset = doc_offset;
+        self
+    }
+
+    /// Attributes the impact segment to the query term at position `term`
+    #[must_use]
+    pub fn of_term(mut self, term: usize) -> Impact {
+        self.term = term;
+        self
+    }
+
     #[must_use]
     pub fn encode<Compressor: crate::compress::Compressor>(
         impact: u16,
@@ -85,9 +111,9 @@ impl Impact {
         )
     }
 
-    pub fn next_large_chunk<'buf, 'index, Compressor: crate::compress::Compressor>(
+    pub fn next_large_chunk<'buf, Compressor: crate::compress::Compressor>(
         &mut self,
-        index_bytes: &'index [u8],
+        index_bytes: &[u8],
         output_buf: &'buf mut compress::LargeBuffer,
     ) -> Option<&'buf compress::LargeBuffer> {
         if self.remaining_u32s >= output_buf.len() {
@@ -107,9 +133,9 @@ impl Impact {
         }
     }
 
-    pub fn next_chunk<'buf, 'index, Compressor: crate::compress::Compressor>(
+    pub fn next_chunk<'buf, Compressor: crate::compress::Compressor>(
         &mut self,
-        index_bytes: &'index [u8],
+        index_bytes: &[u8],
         output_buf: &'buf mut compress::Buffer,
     ) -> Option<&'buf [u32]> {
         // nothing decoded left. decode more
@@ -138,6 +164,25 @@ impl Impact {
             }
         }
     }
+
+    /// Decodes all remaining documents of the segment, calling `f` for each of them
+    ///
+    /// Document identifiers passed to `f` include the `doc_offset` of the segment.
+    pub fn for_each_doc<Compressor: crate::compress::Compressor>(
+        &mut self,
+        index_bytes: &[u8],
+        large_buf: &mut compress::LargeBuffer,
+        buf: &mut compress::Buffer,
+        mut f: impl FnMut(u32),
+    ) {
+        let doc_offset = self.doc_offset;
+        while let Some(chunk) = self.next_large_chunk::<Compressor>(index_bytes, large_buf) {
+            chunk.iter().for_each(|&doc_id| f(doc_id + doc_offset));
+        }
+        while let Some(chunk) = self.next_chunk::<Compressor>(index_bytes, buf) {
+            chunk.iter().for_each(|&doc_id| f(doc_id + doc_offset));
+        }
+    }
 }
 
 #[cfg(test)]
@@ -245,7 +290,7 @@ pub mod tests {
                 }
             }
         }
-        if doc_iter.next() != None {
+        if doc_iter.next().is_some() {
             all_good = false;
         }
         all_good
@@ -3,7 +3,8 @@ use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::convert::TryFrom;
-use std::hash::BuildHasherDefault;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::io::Write;
 use tracing::info;
 use twox_hash::XxHash64;
 
@@ -13,20 +14,53 @@ use rayon::iter::IntoParallelRefIterator;
 use rayon::iter::ParallelIterator;
 use std::cmp::Reverse;
 
+use crate::bitmap::Bitmap;
 use crate::ciff;
+use crate::cost;
+use crate::filter::DocFilter;
+use crate::fusion::Fusion;
 use crate::impact;
 use crate::list;
-use crate::query::{Term, MAX_TERM_WEIGHT};
-use crate::range::Byte;
-use crate::score;
+use crate::query::{Term, TermKind, MAX_TERM_WEIGHT};
+use crate::score::{self, Scorer};
 use crate::search;
+use crate::search::QueryMode;
 use crate::util;
 use crate::ScoreType;
 
+type Vocab = HashMap<String, list::List, BuildHasherDefault<XxHash64>>;
+
+/// Index files start with these magic bytes and format version
+const INDEX_HEADER: util::FileHeader = util::FileHeader {
+    kind: "index",
+    magic: b"IOQP",
+    version: 1,
+    create_with: "`create`",
+};
+
+/// Documents appended to an index after it was created
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+struct Segment {
+    vocab: Vocab,
+    #[serde(with = "serde_bytes")]
+    list_data: Vec<u8>,
+    // identifiers in `list_data` are relative to the first document of the segment
+    doc_offset: u32,
+    num_docs: u32,
+}
+
+/// How the postings of an index were scored, so that appended documents are scored alike
+#[derive(Clone, Copy, serde::Serialize, serde::Deserialize, Debug)]
+struct Scoring {
+    scorer: score::IndexScorer,
+    // average length of the documents the index was created from
+    avg_doc_len: f64,
+}
+
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct Index<C: crate::compress::Compressor> {
     docmap: Vec<String>,
-    vocab: HashMap<String, list::List, BuildHasherDefault<XxHash64>>,
+    vocab: Vocab,
     #[serde(with = "serde_bytes")]
     pub list_data: Vec<u8>,
     num_levels: usize,
@@ -34,9 +68,23 @@ pub struct Index<C: crate::compress::Compressor> {
     max_doc_id: u32,
     max_term_weight: usize,
     num_postings: usize,
-    impact_type: std::marker::PhantomData<C>,
+    quantizer: Option<score::LinearQuantizer>,
+    scoring: Scoring,
+    segments: Vec<Segment>,
+    impact_type: std::marker::PhantomData<fn() -> C>,
     #[serde(skip)]
     search_bufs: parking_lot::Mutex<Vec<search::Scratch>>,
+    #[serde(skip)]
+    deleted: parking_lot::RwLock<Bitmap>,
+    #[serde(skip)]
+    cost_model: Option<cost::CostModel>,
+    // Position of each document when ordered by decreasing static prior, breaks score ties
+    #[serde(skip)]
+    prior_ranks: Option<Vec<u32>>,
+    #[serde(skip)]
+    reverse_docmap: std::sync::OnceLock<HashMap<String, u32, BuildHasherDefault<XxHash64>>>,
+    #[serde(skip)]
+    docmap_hash: std::sync::OnceLock<u64>,
 }
 
 impl<Compressor: crate::compress::Compressor> Index<Compressor> {
@@ -49,27 +97,18 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
     pub fn from_ciff_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
         input_file_name: P,
         quant_bits: u32,
-        scorer: impl score::Scorer,
+        scorer: impl Into<score::IndexScorer>,
     ) -> anyhow::Result<Self> {
+        let scorer = scorer.into();
         let ciff_reader = ciff::Reader::from_file(input_file_name)?;
 
-        let pb_docmap =
-            util::progress_bar("determine docmap", ciff_reader.header.num_docs as usize);
-        let avg_doclen = ciff_reader.header.average_doclength;
         let num_plists = ciff_reader.header.num_postings_lists as usize;
         let num_postings = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
-        let mut docmap = Vec::new();
-        let mut doclen = Vec::new();
         let mut step = 1;
 
         info!("({}) iterate the CIFF data and build the docmap", step);
         step += 1;
-        let mut max_doc_id = 0;
-        for doc_record in ciff_reader.doc_record_iter().progress_with(pb_docmap) {
-            docmap.push(doc_record.collection_docid);
-            doclen.push(f64::from(doc_record.doclength) / avg_doclen);
-            max_doc_id = max_doc_id.max(doc_record.docid as u32);
-        }
+        let (docmap, doclen) = read_doc_records(&ciff_reader)?;
         let num_docs = doclen.len() as u32;
 
         let max_score = if !scorer.needs_quantization() {
@@ -97,15 +136,43 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
             step
         );
         step += 1;
+        let quantizer = scorer
+            .needs_quantization()
+            .then(|| score::LinearQuantizer::new(max_score, quant_bits));
         let encoded_data = Self::quantize_and_encode(
-            max_score,
-            quant_bits,
+            quantizer,
             num_plists,
             &ciff_reader,
             scorer,
             &doclen,
             num_docs,
+            |_, list_len| list_len,
         );
+        let scoring = Scoring {
+            scorer,
+            avg_doc_len: ciff_reader.header.average_doclength,
+        };
+
+        Ok(Self::from_encoded_lists(
+            docmap,
+            encoded_data,
+            num_postings.load(std::sync::atomic::Ordering::Relaxed),
+            quantizer,
+            scoring,
+            step,
+        ))
+    }
+
+    /// Assembles the final index structure from the encoded postings lists of each term
+    fn from_encoded_lists(
+        docmap: Vec<String>,
+        encoded_data: Vec<(String, (list::List, Vec<u8>))>,
+        num_postings: usize,
+        quantizer: Option<score::LinearQuantizer>,
+        scoring: Scoring,
+        mut step: usize,
+    ) -> Self {
+        let max_doc_id = docmap.len().saturating_sub(1) as u32;
 
         info!("({}) determine uniq impact levels ", step);
         step += 1;
@@ -116,27 +183,14 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
             .flat_map(|pl| pl.1 .0.impacts.par_iter().map(|l| l.impact))
             .collect();
 
-        if docmap.len() != (max_doc_id + 1) as usize {
-            anyhow::bail!("Document map length does not match the maximum document identifier. Is your CIFF file corrupt?");
-        }
-
         info!("({}) concatenate final index structure", step);
         step += 1;
-        let pb_write = util::progress_bar("create index", encoded_data.len());
-        let mut vocab: HashMap<_, _, BuildHasherDefault<XxHash64>> =
-            std::collections::HashMap::default();
-        let mut list_data =
-            Vec::with_capacity(encoded_data.iter().map(|(_, (_, data))| data.len()).sum());
-        for (term, (mut list, term_data)) in encoded_data.into_iter().progress_with(pb_write) {
-            list.start_byte_offset = list_data.len();
-            vocab.insert(term, list);
-            list_data.extend_from_slice(&term_data);
-        }
+        let (vocab, list_data) = concatenate_lists(encoded_data);
 
         info!("({}) instantiate search objects", step);
         step += 1;
         let num_levels = uniq_levels.len();
-        let max_level = uniq_levels.into_iter().max().unwrap() as usize;
+        let max_level = uniq_levels.into_iter().max().unwrap_or_default() as usize;
         let search_bufs = parking_lot::Mutex::new(
             (0..2048)
                 .map(|_| search::Scratch::from_index(max_level, MAX_TERM_WEIGHT, max_doc_id))
@@ -144,7 +198,7 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
         );
 
         info!("({}) create final index object", step);
-        Ok(Index {
+        Index {
             docmap,
             vocab,
             list_data,
@@ -152,41 +206,529 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
             max_level,
             max_doc_id,
             max_term_weight: MAX_TERM_WEIGHT,
-            num_postings: num_postings.load(std::sync::atomic::Ordering::Relaxed),
+            num_postings,
+            quantizer,
+            scoring,
+            segments: Vec::new(),
             impact_type: std::marker::PhantomData,
             search_bufs,
-        })
+            deleted: parking_lot::RwLock::default(),
+            cost_model: None,
+            prior_ranks: None,
+            reverse_docmap: std::sync::OnceLock::new(),
+            docmap_hash: std::sync::OnceLock::new(),
+        }
     }
 
-    /// Write IOQP index to file
+    /// Write IOQP index to file, along with its deletions and cost model
+    ///
+    /// Sidecar files left by a previous index at the same path are removed, since its document
+    /// identifiers and costs don't apply to this index.
     ///
     /// # Errors
     /// - fails if file can't be created
     /// - fails if index can't be serialized
+    /// - fails if the sidecar files can't be written or removed
     pub fn write_to_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
         &self,
         output_file_name: P,
     ) -> anyhow::Result<()> {
-        let output_file = std::fs::File::create(output_file_name)?;
-        let output_file = std::io::BufWriter::new(output_file);
+        let output_file = std::fs::File::create(&output_file_name)?;
+        let mut output_file = std::io::BufWriter::new(output_file);
+        INDEX_HEADER.write(&mut output_file)?;
         bincode::serialize_into(output_file, &self)?;
+        if self.num_deleted() > 0 {
+            self.write_deletions(&output_file_name)?;
+        } else {
+            remove_if_exists(deletions_path(&output_file_name))?;
+        }
+        match &self.cost_model {
+            Some(cost_model) => cost_model.write_to_file(cost::sidecar_path(&output_file_name))?,
+            None => remove_if_exists(cost::sidecar_path(&output_file_name))?,
+        }
         Ok(())
     }
 
-    /// Read IOQP index from file
+    /// Read IOQP index from file, along with its deletions if present
     ///
     /// # Errors
     /// - fails if file does not exist
-    /// - fails if index can't be deserialized
+    /// - fails if the file is not an index of the current format version
+    /// - fails if index or deletions can't be deserialized
     pub fn read_from_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
         index_file_name: P,
     ) -> anyhow::Result<Self> {
-        let input_file = std::fs::File::open(index_file_name)?;
-        let input_file = std::io::BufReader::new(input_file);
-        let index = bincode::deserialize_from::<_, Self>(input_file)?;
+        let input_file = std::fs::File::open(&index_file_name)?;
+        let mut input_file = std::io::BufReader::new(input_file);
+        INDEX_HEADER.check(&mut input_file, &index_file_name)?;
+        let mut index = bincode::deserialize_from::<_, Self>(input_file)?;
+        let deletions_file_name = deletions_path(&index_file_name);
+        if deletions_file_name.exists() {
+            index.read_deletions(deletions_file_name)?;
+        }
+        let cost_file_name = cost::sidecar_path(&index_file_name);
+        if cost_file_name.exists() {
+            index.cost_model = Some(cost::CostModel::read_from_file(cost_file_name)?);
+        }
         Ok(index)
     }
 
+    /// Write the deleted documents to the file stored alongside the index at `index_file_name`
+    ///
+    /// The deletions are written to a temporary file that replaces the previous file once it is
+    /// synced, so a crash never leaves a truncated file behind. Callers writing the deletions of
+    /// the same index concurrently have to serialize the calls.
+    ///
+    /// # Errors
+    /// - fails if file can't be created
+    /// - fails if deletions can't be serialized
+    /// - fails if the file can't be synced or renamed
+    pub fn write_deletions<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        &self,
+        index_file_name: P,
+    ) -> anyhow::Result<()> {
+        let deletions_file_name = deletions_path(index_file_name);
+        let mut temp_file_name = deletions_file_name.clone().into_os_string();
+        temp_file_name.push(".tmp");
+        let output_file = std::fs::File::create(&temp_file_name)?;
+        let mut output_file = std::io::BufWriter::new(output_file);
+        bincode::serialize_into(&mut output_file, &*self.deleted.read())?;
+        output_file.flush()?;
+        output_file.get_ref().sync_all()?;
+        std::fs::rename(&temp_file_name, &deletions_file_name)?;
+        Ok(())
+    }
+
+    fn read_deletions(&self, deletions_file_name: std::path::PathBuf) -> anyhow::Result<()> {
+        let input_file = std::fs::File::open(deletions_file_name)?;
+        let input_file = std::io::BufReader::new(input_file);
+        *self.deleted.write() = bincode::deserialize_from::<_, Bitmap>(input_file)?;
+        Ok(())
+    }
+
+    /// Marks documents as deleted so they no longer appear in any results
+    ///
+    /// Returns the number of documents that were not already deleted.
+    pub fn delete_doc_ids(&self, doc_ids: &[u32]) -> usize {
+        let mut deleted = self.deleted.write();
+        let mut num_deleted = 0;
+        for &doc_id in doc_ids {
+            if doc_id <= self.max_doc_id && !deleted.contains(doc_id) {
+                deleted.insert(doc_id);
+                num_deleted += 1;
+            }
+        }
+        num_deleted
+    }
+
+    /// Marks documents as deleted given their external collection docids
+    pub fn delete_collection_doc_ids<S: AsRef<str>>(&self, collection_doc_ids: &[S]) -> usize {
+        self.delete_doc_ids(&self.resolve_doc_ids(collection_doc_ids))
+    }
+
+    pub fn num_deleted(&self) -> usize {
+        self.deleted.read().len()
+    }
+
+    /// Rewrites the index without its deleted documents
+    ///
+    /// Document identifiers are renumbered to stay dense.
+    #[must_use]
+    pub fn compact(&self) -> Self {
+        let deleted = self.deleted.read();
+        let mut docmap = Vec::with_capacity(self.docmap.len() - deleted.len());
+        let mut doc_id_map = Vec::with_capacity(self.docmap.len());
+        for (doc_id, name) in self.docmap.iter().enumerate() {
+            if deleted.contains(doc_id as u32) {
+                doc_id_map.push(None);
+            } else {
+                doc_id_map.push(Some(docmap.len() as u32));
+                docmap.push(name.clone());
+            }
+        }
+
+        info!(
+            "(1) rewrite postings without {} deleted docs",
+            deleted.len()
+        );
+        let terms = self.terms();
+        let pb_compact = util::progress_bar("compact postings", terms.len());
+        let encoded_data: Vec<_> = terms
+            .into_par_iter()
+            .progress_with(pb_compact)
+            .filter_map(|term| {
+                let postings: Vec<(u16, Vec<u32>)> = self
+                    .decode_term(term)
+                    .into_iter()
+                    .map(|(impact, docs)| {
+                        let docs = docs
+                            .into_iter()
+                            .filter_map(|doc_id| doc_id_map[doc_id as usize])
+                            .collect::<Vec<u32>>();
+                        (impact, docs)
+                    })
+                    .filter(|(_, docs)| !docs.is_empty())
+                    .collect();
+                if postings.is_empty() {
+                    None
+                } else {
+                    Some((term.clone(), list::List::encode::<Compressor>(&postings)))
+                }
+            })
+            .collect();
+        let num_postings = encoded_data
+            .iter()
+            .flat_map(|(_, (list, _))| list.impacts.iter().map(|m| m.count as usize))
+            .sum();
+        Self::from_encoded_lists(
+            docmap,
+            encoded_data,
+            num_postings,
+            self.quantizer,
+            self.scoring,
+            2,
+        )
+    }
+
+    /// Merges indexes built with the same scorer and number of quantization bits into one index
+    ///
+    /// Documents of each index follow those of the previous one. Deleted documents stay deleted.
+    /// Indexes quantized with a lower global max than the largest one are re-quantized to it.
+    ///
+    /// # Errors
+    /// - No indexes are given
+    /// - The indexes were scored or quantized with different parameters
+    pub fn merge(indexes: &[Self]) -> anyhow::Result<Self> {
+        let Some(first) = indexes.first() else {
+            anyhow::bail!("No indexes to merge");
+        };
+        let quant_bits = |index: &Self| index.quantizer.map(|quantizer| quantizer.quant_bits());
+        if let Some(other) = indexes.iter().find(|index| {
+            quant_bits(index) != quant_bits(first) || index.scoring.scorer != first.scoring.scorer
+        }) {
+            anyhow::bail!(
+                "Indexes were scored or quantized differently: {:?} with {:?} vs {:?} with {:?}",
+                first.scoring.scorer,
+                first.quantizer,
+                other.scoring.scorer,
+                other.quantizer
+            );
+        }
+        let quantizer = first.quantizer.map(|quantizer| {
+            let global_max = indexes
+                .iter()
+                .filter_map(|index| index.quantizer)
+                .map(|quantizer| quantizer.global_max())
+                .fold(0.0, f32::max);
+            score::LinearQuantizer::new(global_max, quantizer.quant_bits())
+        });
+
+        info!("(1) concatenate docmaps of {} indexes", indexes.len());
+        let mut docmap = Vec::with_capacity(indexes.iter().map(|index| index.docmap.len()).sum());
+        let mut doc_offsets = Vec::with_capacity(indexes.len());
+        for index in indexes {
+            doc_offsets.push(docmap.len() as u32);
+            docmap.extend_from_slice(&index.docmap);
+        }
+
+        info!("(2) merge postings per impact level");
+        let terms: HashSet<&String> = indexes.iter().flat_map(Self::terms).collect();
+        let terms: Vec<&String> = terms.into_iter().collect();
+        let pb_merge = util::progress_bar("merge postings", terms.len());
+        let encoded_data: Vec<_> = terms
+            .into_par_iter()
+            .progress_with(pb_merge)
+            .map(|term| {
+                let postings = merge_postings(indexes.iter().zip(&doc_offsets).map(
+                    |(index, &doc_offset)| {
+                        let mut postings = index.decode_term(term);
+                        if let (Some(from), Some(to)) = (index.quantizer, quantizer) {
+                            if from != to {
+                                postings = requantize(postings, from, to);
+                            }
+                        }
+                        shift_doc_ids(&mut postings, doc_offset);
+                        postings
+                    },
+                ));
+                (term.clone(), list::List::encode::<Compressor>(&postings))
+            })
+            .collect();
+        let num_postings = indexes.iter().map(|index| index.num_postings).sum();
+        let num_docs = docmap.len().max(1) as f64;
+        let scoring = Scoring {
+            scorer: first.scoring.scorer,
+            avg_doc_len: indexes
+                .iter()
+                .map(|index| index.scoring.avg_doc_len * index.docmap.len() as f64 / num_docs)
+                .sum(),
+        };
+        let merged =
+            Self::from_encoded_lists(docmap, encoded_data, num_postings, quantizer, scoring, 3);
+
+        for (index, &doc_offset) in indexes.iter().zip(&doc_offsets) {
+            let deleted: Vec<u32> = index
+                .deleted
+                .read()
+                .iter()
+                .map(|d| d + doc_offset)
+                .collect();
+            merged.delete_doc_ids(&deleted);
+        }
+        Ok(merged)
+    }
+
+    /// Appends the documents of a CIFF file to the index as a new segment
+    ///
+    /// New documents are scored with the scorer of the index and the statistics of the
+    /// documents it was created from: their number, average length and the document frequency
+    /// of each term, or its frequency in the CIFF file for terms new to the index. Scores are
+    /// quantized with the quantizer of the index, clamping scores above its global max.
+    ///
+    /// # Errors
+    /// - Can't open ciff file
+    pub fn append_ciff_file<P: AsRef<std::path::Path> + std::fmt::Debug>(
+        &mut self,
+        input_file_name: P,
+    ) -> anyhow::Result<()> {
+        let ciff_reader = ciff::Reader::from_file(input_file_name)?;
+        let num_plists = ciff_reader.header.num_postings_lists as usize;
+
+        info!("(1) iterate the CIFF data and build the docmap");
+        let (docmap, mut doclen) = read_doc_records(&ciff_reader)?;
+        let num_docs = doclen.len() as u32;
+        // lengths relative to the documents of the index rather than those of the file
+        let len_scale = ciff_reader.header.average_doclength / self.scoring.avg_doc_len;
+        doclen.iter_mut().for_each(|len| *len *= len_scale);
+
+        info!("(2) iterate the CIFF data score + quantize + encode");
+        // the postings outside of segments are those of the documents the index was created from
+        let base_docs = self
+            .segments
+            .first()
+            .map_or(self.docmap.len() as u32, |segment| segment.doc_offset);
+        let encoded_data = Self::quantize_and_encode(
+            self.quantizer,
+            num_plists,
+            &ciff_reader,
+            self.scoring.scorer,
+            &doclen,
+            base_docs,
+            |term, list_len| {
+                self.vocab.get(term).map_or(list_len, |list| {
+                    list.impacts.iter().map(|impact| impact.count).sum()
+                })
+            },
+        );
+        self.num_postings += encoded_data
+            .iter()
+            .flat_map(|(_, (list, _))| list.impacts.iter().map(|m| m.count as usize))
+            .sum::<usize>();
+
+        info!("(3) append segment with {} docs", num_docs);
+        let (vocab, list_data) = concatenate_lists(encoded_data);
+        self.segments.push(Segment {
+            vocab,
+            list_data,
+            doc_offset: self.docmap.len() as u32,
+            num_docs,
+        });
+        self.docmap.extend(docmap);
+        // the costs were calibrated without the new documents
+        self.cost_model = None;
+        self.refresh_search_state();
+        Ok(())
+    }
+
+    /// Merges runs of adjacent appended segments with at most `max_docs` documents each
+    ///
+    /// Returns the number of appended segments after merging.
+    pub fn merge_small_segments(&mut self, max_docs: usize) -> usize {
+        let mut merged: Vec<Segment> = Vec::with_capacity(self.segments.len());
+        let mut run: Vec<Segment> = Vec::new();
+        for segment in std::mem::take(&mut self.segments) {
+            if segment.num_docs as usize <= max_docs {
+                run.push(segment);
+            } else {
+                merged.extend(Self::merge_segment_run(std::mem::take(&mut run)));
+                merged.push(segment);
+            }
+        }
+        merged.extend(Self::merge_segment_run(run));
+        self.segments = merged;
+        self.refresh_search_state();
+        self.segments.len()
+    }
+
+    fn merge_segment_run(run: Vec<Segment>) -> Option<Segment> {
+        if run.len() < 2 {
+            return run.into_iter().next();
+        }
+        info!("merge {} small segments", run.len());
+        let doc_offset = run[0].doc_offset;
+        let num_docs = run.iter().map(|segment| segment.num_docs).sum();
+        let terms: HashSet<&String> = run.iter().flat_map(|s| s.vocab.keys()).collect();
+        let encoded_data: Vec<_> = terms
+            .into_par_iter()
+            .map(|term| {
+                let postings = merge_postings(run.iter().filter_map(|segment| {
+                    segment.vocab.get(term).map(|list| {
+                        let mut postings = list.decode::<Compressor>(&segment.list_data);
+                        shift_doc_ids(&mut postings, segment.doc_offset - doc_offset);
+                        postings
+                    })
+                }));
+                (term.clone(), list::List::encode::<Compressor>(&postings))
+            })
+            .collect();
+        let (vocab, list_data) = concatenate_lists(encoded_data);
+        Some(Segment {
+            vocab,
+            list_data,
+            doc_offset,
+            num_docs,
+        })
+    }
+
+    /// Updates the statistics and search buffers after documents were added
+    fn refresh_search_state(&mut self) {
+        let uniq_levels: HashSet<u16> = std::iter::once(&self.vocab)
+            .chain(self.segments.iter().map(|segment| &segment.vocab))
+            .flat_map(|vocab| vocab.values())
+            .flat_map(|list| list.impacts.iter().map(|m| m.impact))
+            .collect();
+        self.num_levels = uniq_levels.len();
+        self.max_level = uniq_levels.into_iter().max().unwrap_or_default() as usize;
+        self.max_doc_id = self.docmap.len().saturating_sub(1) as u32;
+        self.search_bufs.get_mut().clear();
+        self.reverse_docmap = std::sync::OnceLock::new();
+        self.docmap_hash = std::sync::OnceLock::new();
+    }
+
+    /// Number of segments appended since the index was created
+    pub fn num_segments(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Sets the latency model used to select budgets for `QueryMode::Target`
+    pub fn set_cost_model(&mut self, cost_model: cost::CostModel) {
+        self.cost_model = Some(cost_model);
+    }
+
+    pub fn cost_model(&self) -> Option<&cost::CostModel> {
+        self.cost_model.as_ref()
+    }
+
+    /// The postings of the impact groups of a query, in the order they are processed
+    pub fn impact_group_counts(&self, tokens: &[Term]) -> Vec<u32> {
+        let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
+            search::Scratch::from_index(self.max_level, self.max_term_weight, self.max_doc_id)
+        });
+        self.determine_impact_segments(&mut search_buf, tokens);
+        let counts = Position::start(&search_buf)
+            .remaining_groups(&search_buf)
+            .map(impact::Impact::count)
+            .collect();
+        self.search_bufs.lock().push(search_buf);
+        counts
+    }
+
+    /// Turns a target latency into a postings budget for the rest of a query
+    ///
+    /// Without a cost model the target is used as a deadline instead.
+    fn target_budget(
+        &self,
+        data: &search::Scratch,
+        position: &Position,
+        target: std::time::Duration,
+    ) -> QueryMode {
+        match &self.cost_model {
+            Some(cost_model) => {
+                let counts: smallvec::SmallVec<[u32; 64]> = position
+                    .remaining_groups(data)
+                    .map(impact::Impact::count)
+                    .collect();
+                QueryMode::Fixed(cost_model.budget(&counts, target))
+            }
+            None => {
+                static NO_COST_MODEL: std::sync::Once = std::sync::Once::new();
+                NO_COST_MODEL.call_once(|| {
+                    tracing::warn!("index has no cost model, using target latencies as deadlines");
+                });
+                QueryMode::Deadline(target)
+            }
+        }
+    }
+
+    /// The quantizer used to create the index, if its scores were quantized
+    pub fn quantizer(&self) -> Option<score::LinearQuantizer> {
+        self.quantizer
+    }
+
+    /// Approximate original score of a document scored `score` by a query
+    ///
+    /// `weight_scale` undoes the rescaling of the query term weights, see `Query::weight_scale`.
+    pub fn dequantize(&self, score: ScoreType, weight_scale: f32) -> f32 {
+        let score = self.quantizer.map_or(f32::from(score), |quantizer| {
+            quantizer.dequantize(u32::from(score))
+        });
+        score * weight_scale
+    }
+
+    /// Adds the approximate original scores to the results of a query
+    pub fn add_real_scores(&self, results: &mut search::Results, weight_scale: f32) {
+        for result in &mut results.topk {
+            result.real_score = Some(self.dequantize(result.score, weight_scale));
+        }
+    }
+
+    /// All terms of the index, across all segments
+    fn terms(&self) -> Vec<&String> {
+        let terms: HashSet<&String> = std::iter::once(&self.vocab)
+            .chain(self.segments.iter().map(|segment| &segment.vocab))
+            .flat_map(|vocab| vocab.keys())
+            .collect();
+        terms.into_iter().collect()
+    }
+
+    /// The lists of a term in each segment as `(segment, doc_offset, list)`
+    fn term_lists<'a>(
+        &'a self,
+        token: &'a str,
+    ) -> impl Iterator<Item = (usize, u32, &'a list::List)> + 'a {
+        self.vocab
+            .get(token)
+            .map(|list| (0, 0, list))
+            .into_iter()
+            .chain(
+                self.segments
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(idx, segment)| {
+                        segment
+                            .vocab
+                            .get(token)
+                            .map(|list| (idx + 1, segment.doc_offset, list))
+                    }),
+            )
+    }
+
+    fn segment_data(&self, segment: usize) -> &[u8] {
+        match segment {
+            0 => &self.list_data,
+            _ => &self.segments[segment - 1].list_data,
+        }
+    }
+
+    /// Decodes the postings of a term across all segments using global document identifiers
+    fn decode_term(&self, term: &str) -> Vec<(u16, Vec<u32>)> {
+        merge_postings(self.term_lists(term).map(|(segment, doc_offset, list)| {
+            let mut postings = list.decode::<Compressor>(self.segment_data(segment));
+            shift_doc_ids(&mut postings, doc_offset);
+            postings
+        }))
+    }
+
     pub fn impact_list(&self, token: &str) -> Option<&list::List> {
         self.vocab.get(token)
     }
@@ -211,148 +753,686 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
         &self.docmap
     }
 
+    /// Hash of the collection docids in identifier order, equal for indexes of the same documents
+    pub fn docmap_hash(&self) -> u64 {
+        *self.docmap_hash.get_or_init(|| {
+            let mut hasher = XxHash64::default();
+            self.docmap.hash(&mut hasher);
+            hasher.finish()
+        })
+    }
+
+    /// Maps external collection docids to internal document identifiers
+    ///
+    /// The reverse docmap is built on first use. Unknown docids are skipped.
+    pub fn resolve_doc_ids<S: AsRef<str>>(&self, collection_doc_ids: &[S]) -> Vec<u32> {
+        let reverse_docmap = self.reverse_docmap.get_or_init(|| {
+            self.docmap
+                .iter()
+                .enumerate()
+                .map(|(doc_id, name)| (name.clone(), doc_id as u32))
+                .collect()
+        });
+        collection_doc_ids
+            .iter()
+            .filter_map(|name| match reverse_docmap.get(name.as_ref()) {
+                Some(&doc_id) => Some(doc_id),
+                None => {
+                    tracing::warn!("unknown collection docid '{}'", name.as_ref());
+                    None
+                }
+            })
+            .collect()
+    }
+
     fn determine_impact_segments(&self, data: &mut search::Scratch, tokens: &[Term]) -> usize {
         // determine what to decompress
         data.impacts.iter_mut().for_each(std::vec::Vec::clear);
-        tokens
+        data.penalties.clear();
+        data.exclusions.clear();
+        data.term_levels.clear();
+        data.offset = 0;
+        let total_postings = tokens
             .iter()
-            .filter_map(|tok| match self.impact_list(&tok.token) {
-                Some(list) => {
-                    let mut start = list.start_byte_offset;
-                    Some(
-                        list.impacts
-                            .iter()
-                            .map(|ti| {
-                                let stop = start + ti.bytes as usize;
-                                data.impacts[ti.impact as usize * tok.freq as usize].push(
-                                    impact::Impact::from_encoded_slice_weighted(
-                                        *ti,
-                                        Byte::new(start, stop),
-                                        tok.freq as u16,
-                                    ),
-                                );
-                                start += ti.bytes as usize;
-                                ti.count
+            .enumerate()
+            .filter_map(|(term, tok)| {
+                let lists: smallvec::SmallVec<[_; 4]> = self.term_lists(&tok.token).collect();
+                if lists.is_empty() {
+                    tracing::warn!("unknown query token '{}'", tok);
+                    return None;
+                }
+                let segments = lists.into_iter().flat_map(|(segment, doc_offset, list)| {
+                    list.segments(tok.freq as u16)
+                        .map(move |impact| impact.in_segment(segment, doc_offset).of_term(term))
+                });
+                match tok.kind {
+                    TermKind::Positive => Some(
+                        segments
+                            .map(|segment| {
+                                let count = segment.count();
+                                data.term_levels.push((term, segment.impact()));
+                                data.impacts[segment.impact() as usize].push(segment);
+                                count
                             })
                             .sum::<u32>(),
-                    )
-                }
-                None => {
-                    tracing::warn!("unknown query token '{}'", tok);
-                    None
+                    ),
+                    TermKind::Negative => {
+                        // start all accumulators high enough to absorb the largest penalty
+                        let start = data.penalties.len();
+                        data.penalties.extend(segments);
+                        let max_penalty = data.penalties[start..]
+                            .iter()
+                            .map(impact::Impact::impact)
+                            .max()
+                            .unwrap_or_default();
+                        data.offset = data.offset.saturating_add(max_penalty);
+                        None
+                    }
+                    TermKind::Exclude => {
+                        data.exclusions.extend(segments);
+                        None
+                    }
                 }
             })
-            .sum::<u32>() as usize
+            .sum::<u32>() as usize;
+        if data.fit_offset() {
+            tracing::warn!("penalties of the query are capped to fit the score accumulators");
+        }
+        total_postings
+    }
+
+    /// Query terms that don't occur in the index
+    fn unknown_terms(&self, tokens: &[Term]) -> Vec<String> {
+        tokens
+            .iter()
+            .filter(|tok| self.term_lists(&tok.token).next().is_none())
+            .map(|tok| tok.token.clone())
+            .collect()
+    }
+
+    /// Whether a query with `positive_postings` postings touches few enough accumulators for
+    /// sparse accumulators to be cheaper
+    ///
+    /// Even if every posting lands on a different page, initializing the touched pages costs no
+    /// more than resetting all accumulators.
+    fn prefers_sparse(&self, data: &search::Scratch, positive_postings: usize) -> bool {
+        let penalty_postings: usize = data.penalties.iter().map(|p| p.count() as usize).sum();
+        (positive_postings + penalty_postings).saturating_mul(search::CHUNK_SIZE)
+            < self.max_doc_id as usize + 1
+    }
+
+    /// Resets the accumulators and applies the negative and exclusion terms of the query
+    fn prepare_accumulators(&self, data: &mut search::Scratch, sparse: bool) {
+        let offset = data.offset;
+        data.sparse = sparse;
+        data.pages.clear();
+        if !sparse {
+            data.accumulators.iter_mut().for_each(|x| *x = offset);
+            data.chunk.iter_mut().for_each(|x| *x = 0);
+        }
+        data.clear_excluded();
+        self.apply_penalties(data);
+    }
+
+    /// Subtracts the negative terms of the query from the accumulators and marks the documents of
+    /// its exclusion terms
+    fn apply_penalties(&self, data: &mut search::Scratch) {
+        let offset = data.offset;
+        let sparse = data.sparse;
+        let accumulators = &mut data.accumulators;
+        let chunks = &mut data.chunk;
+        let pages = &mut data.pages;
+        // negative and exclusion terms are always processed in full
+        for penalty in &mut data.penalties {
+            let impact = penalty.impact() as ScoreType;
+            penalty.for_each_doc::<Compressor>(
+                self.segment_data(penalty.segment),
+                &mut data.large_decode_buf,
+                &mut data.decode_buf,
+                |doc_id| {
+                    if sparse {
+                        pages.touch(doc_id as usize, accumulators, chunks, offset);
+                    }
+                    let accum = &mut accumulators[doc_id as usize];
+                    *accum = accum.saturating_sub(impact);
+                },
+            );
+        }
+        let excluded = &mut data.excluded;
+        let excluded_docs = &mut data.excluded_docs;
+        for exclusion in &mut data.exclusions {
+            exclusion.for_each_doc::<Compressor>(
+                self.segment_data(exclusion.segment),
+                &mut data.large_decode_buf,
+                &mut data.decode_buf,
+                |doc_id| {
+                    if !excluded.contains(doc_id) {
+                        excluded.insert(doc_id);
+                        excluded_docs.push(doc_id);
+                    }
+                },
+            );
+        }
     }
 
-    fn process_impact_segments(&self, data: &mut search::Scratch, mut postings_budget: i64) {
+    /// Processes impact segments in decreasing impact order from `position` until one of the
+    /// limits is reached
+    ///
+    /// Rank-safe termination only considers documents admitted by `filter`. Returns whether the
+    /// top-k is guaranteed to be exact.
+    fn process_impact_segments(
+        &self,
+        data: &mut search::Scratch,
+        position: &mut Position,
+        limits: Limits,
+        filter: Option<&DocFilter>,
+        stats: &mut search::Stats,
+    ) -> bool {
+        let Limits {
+            mut postings_budget,
+            deadline,
+            rank_safe_k,
+            term_budget,
+            max_groups,
+            min_impact,
+        } = limits;
+        let past_deadline =
+            || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline);
+        let offset = data.offset;
+        let sparse = data.sparse;
         let accumulators = &mut data.accumulators;
         let chunks = &mut data.chunk;
-        accumulators.iter_mut().for_each(|x| *x = 0);
-        chunks.iter_mut().for_each(|x| *x = 0);
-        let impact_iter = data.impacts.iter_mut().rev().flat_map(|i| i.iter_mut());
-        for impact_group in impact_iter {
-            if postings_budget < 0 {
+        let pages = &mut data.pages;
+        // a check scans the chunk maxima, so only check after processing as many postings
+        let mut postings_since_check = 0;
+        let mut num_groups = 0;
+        let num_terms = data.term_levels.last().map_or(0, |&(term, _)| term + 1);
+        let mut term_postings: smallvec::SmallVec<[i64; 16]> = smallvec::smallvec![0; num_terms];
+        // groups skipped by an earlier term budget are revisited first
+        let mut revisit = 0;
+        loop {
+            let revisiting = revisit < position.skipped.len();
+            let (level, group) = if revisiting {
+                position.skipped[revisit]
+            } else if position.levels > 0 {
+                let level = position.levels - 1;
+                let num_level_groups = data.impacts[level].len();
+                if position.group >= num_level_groups {
+                    position.levels -= 1;
+                    position.group = 0;
+                    continue;
+                }
+                // rank-safe checks happen before starting a level
+                if position.group == 0 {
+                    if (level as ScoreType) < min_impact {
+                        return false;
+                    }
+                    // the upper bound only covers groups of this level and below
+                    let bounded = position.skipped.is_empty();
+                    if let Some(k) = rank_safe_k.filter(|_| bounded) {
+                        if postings_since_check >= chunks.len() {
+                            postings_since_check = 0;
+                            let remaining =
+                                remaining_upper_bound(&data.term_levels, level as ScoreType);
+                            let tie_key = self.tie_keys(&data.excluded, filter);
+                            let touched = if sparse { Some(pages.touched()) } else { None };
+                            let heap = &mut data.heap;
+                            fill_topk_heap(
+                                heap,
+                                accumulators,
+                                chunks,
+                                touched,
+                                offset,
+                                k + 1,
+                                tie_key,
+                            );
+                            // documents missing from the heap score at most the offset
+                            let floor = u32::from(offset);
+                            let kth_next = if heap.len() > k {
+                                heap.pop().map_or(0, |r| u32::from(r.score))
+                            } else {
+                                0
+                            };
+                            let kth = if heap.len() >= k {
+                                heap.peek().map_or(0, |r| u32::from(r.score))
+                            } else {
+                                floor
+                            };
+                            let kth_next = kth_next.max(floor);
+                            if kth > kth_next + remaining {
+                                return true;
+                            }
+                        }
+                    }
+                }
+                (level, position.group)
+            } else {
                 break;
+            };
+            if postings_budget < 0 || num_groups >= max_groups || past_deadline() {
+                return false;
             }
+            let impact_group = &mut data.impacts[level][group];
             let num_postings = impact_group.count() as i64;
+            if term_budget.is_some_and(|budget| term_postings[impact_group.term] > budget) {
+                if revisiting {
+                    revisit += 1;
+                } else {
+                    position.skipped.push((level, group));
+                    position.group += 1;
+                }
+                continue;
+            }
             let impact = impact_group.impact();
-            while let Some(chunk) = impact_group
-                .next_large_chunk::<Compressor>(&self.list_data, &mut data.large_decode_buf)
+            let list_data = self.segment_data(impact_group.segment);
+            let doc_offset = impact_group.doc_offset as usize;
+            while let Some(chunk) =
+                impact_group.next_large_chunk::<Compressor>(list_data, &mut data.large_decode_buf)
             {
-                chunk.iter().cloned().for_each(|doc_id| {
-                    let doc_id = doc_id as usize;
-                    let chunk_id = doc_id >> search::CHUNK_SHIFT;
-                    let accum = unsafe { accumulators.get_unchecked_mut(doc_id) };
-                    *accum += impact as ScoreType;
-                    let chnk = unsafe { chunks.get_unchecked_mut(chunk_id) };
-                    *chnk = (*chnk).max(*accum);
-                });
+                if past_deadline() {
+                    return false;
+                }
+                if sparse {
+                    accumulate::<true>(
+                        chunk,
+                        doc_offset,
+                        impact as ScoreType,
+                        accumulators,
+                        chunks,
+                        pages,
+                        offset,
+                    );
+                } else {
+                    accumulate::<false>(
+                        chunk,
+                        doc_offset,
+                        impact as ScoreType,
+                        accumulators,
+                        chunks,
+                        pages,
+                        offset,
+                    );
+                }
             }
             while let Some(chunk) =
-                impact_group.next_chunk::<Compressor>(&self.list_data, &mut data.decode_buf)
+                impact_group.next_chunk::<Compressor>(list_data, &mut data.decode_buf)
             {
-                chunk.iter().cloned().for_each(|doc_id| {
-                    let doc_id = doc_id as usize;
-                    let chunk_id = doc_id >> search::CHUNK_SHIFT;
-                    let accum = unsafe { accumulators.get_unchecked_mut(doc_id) };
-                    *accum += impact as ScoreType;
-                    let chnk = unsafe { chunks.get_unchecked_mut(chunk_id) };
-                    *chnk = (*chnk).max(*accum);
-                });
+                if sparse {
+                    accumulate::<true>(
+                        chunk,
+                        doc_offset,
+                        impact as ScoreType,
+                        accumulators,
+                        chunks,
+                        pages,
+                        offset,
+                    );
+                } else {
+                    accumulate::<false>(
+                        chunk,
+                        doc_offset,
+                        impact as ScoreType,
+                        accumulators,
+                        chunks,
+                        pages,
+                        offset,
+                    );
+                }
             }
             postings_budget -= num_postings;
+            postings_since_check += num_postings as usize;
+            term_postings[impact_group.term] += num_postings;
+            num_groups += 1;
+            stats.processed_postings += num_postings as usize;
+            stats.processed_segments += 1;
+            if revisiting {
+                position.skipped.remove(revisit);
+            } else {
+                position.group += 1;
+            }
         }
+        // the top-k is only exact once no skipped group is left
+        position.skipped.is_empty()
     }
 
-    fn determine_topk_chunks(&self, data: &mut search::Scratch, k: usize) -> Vec<search::Result> {
-        let heap = &mut data.heap;
-        let accumulators = &data.accumulators;
-        let chunks = &data.chunk;
-        heap.clear();
-
-        // Calculate how many chunks we need to look at to populate k docs into the heap
-        let init_heap_chunks = ((k as f32) / (search::CHUNK_SIZE as f32)).ceil() as usize;
-        let init_heap_docs = search::CHUNK_SIZE * init_heap_chunks;
+    /// Processes the selected impact segments of a query with one worker per document range and
+    /// merges the top-k of the workers
+    ///
+    /// Segments carry no skip information, so every worker decodes each segment from its start up
+    /// to the end of its range. Only accumulation and top-k selection are split, decoding is not.
+    ///
+    /// Returns the top-k, the number of matched documents and whether the top-k is guaranteed to
+    /// be exact.
+    fn process_partitioned(
+        &self,
+        data: &mut search::Scratch,
+        limits: &Limits,
+        partitions: &[std::ops::Range<usize>],
+        k: usize,
+        filter: Option<&DocFilter>,
+        stats: &mut search::Stats,
+    ) -> (Vec<search::Result>, usize, bool) {
+        let accumulation_start = std::time::Instant::now();
+        let (groups, complete) = select_groups(&data.impacts, &data.term_levels, limits);
+        stats.processed_segments = groups.len();
+        stats.processed_postings = groups.iter().map(|group| group.count() as usize).sum();
+        let offset = data.offset;
+        let deadline = limits.deadline;
+        let past_deadline =
+            || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline);
 
-        // Push the first k documents
-        accumulators[..k]
-            .iter()
-            .enumerate()
-            .for_each(|(doc_id, score)| {
-                heap.push(search::Result {
-                    doc_id: doc_id as u32,
-                    score: *score,
-                });
-            });
+        // split the accumulators and chunk maxima along the chunk aligned partitions
+        let mut slices = Vec::with_capacity(partitions.len());
+        let mut accumulators = &mut data.accumulators[..];
+        let mut chunks = &mut data.chunk[..];
+        for (idx, range) in partitions.iter().enumerate() {
+            let (accs, rest) = std::mem::take(&mut accumulators).split_at_mut(range.len());
+            accumulators = rest;
+            let num_chunks = if idx + 1 == partitions.len() {
+                chunks.len()
+            } else {
+                range.len() >> search::CHUNK_SHIFT
+            };
+            let (chnks, rest) = std::mem::take(&mut chunks).split_at_mut(num_chunks);
+            chunks = rest;
+            slices.push((range.start, accs, chnks));
+        }
 
+        let tie_key = self.tie_keys(&data.excluded, filter);
+        let partition_results: Vec<(Vec<search::Result>, usize, bool)> = slices
+            .into_par_iter()
+            .map(|(first_doc, accs, chnks)| {
+                let end_doc = first_doc + accs.len();
+                let mut large_decode_buf = Box::new([0; crate::compress::LARGE_BLOCK_LEN]);
+                let mut decode_buf = [0; crate::compress::BLOCK_LEN];
+                let mut finished = true;
+                'groups: for &group in &groups {
+                    if past_deadline() {
+                        finished = false;
+                        break;
+                    }
+                    let mut impact_group = group.clone();
+                    let impact = impact_group.impact() as ScoreType;
+                    let list_data = self.segment_data(impact_group.segment);
+                    let doc_offset = impact_group.doc_offset as usize;
+                    // documents are sorted, so stop decoding once they leave the partition
+                    let mut accumulate = |doc_id: &u32| {
+                        let doc_id = *doc_id as usize + doc_offset;
+                        if doc_id >= first_doc && doc_id < end_doc {
+                            let accum = &mut accs[doc_id - first_doc];
+                            *accum += impact;
+                            let chnk = &mut chnks[(doc_id - first_doc) >> search::CHUNK_SHIFT];
+                            *chnk = (*chnk).max(*accum);
+                        }
+                        doc_id < end_doc
+                    };
+                    while let Some(chunk) = impact_group
+                        .next_large_chunk::<Compressor>(list_data, &mut large_decode_buf)
+                    {
+                        if past_deadline() {
+                            finished = false;
+                            break 'groups;
+                        }
+                        if !chunk.iter().all(&mut accumulate) {
+                            continue 'groups;
+                        }
+                    }
+                    while let Some(chunk) =
+                        impact_group.next_chunk::<Compressor>(list_data, &mut decode_buf)
+                    {
+                        if !chunk.iter().all(&mut accumulate) {
+                            continue 'groups;
+                        }
+                    }
+                }
+                let mut heap = std::collections::BinaryHeap::with_capacity(k + 1);
+                let matched = fill_topk_heap(&mut heap, accs, chnks, None, offset, k, |doc_id| {
+                    tie_key(doc_id + first_doc as u32)
+                });
+                let topk = heap
+                    .into_iter()
+                    .map(|mut result| {
+                        result.doc_id += first_doc as u32;
+                        result
+                    })
+                    .collect();
+                (topk, matched, finished)
+            })
+            .collect();
 
-        // Check the remaining init_heap_docs - k entries
-        let mut threshold = heap.peek().unwrap().score;
-        accumulators[k..init_heap_docs]
+        let topk_start = std::time::Instant::now();
+        stats.accumulation += topk_start - accumulation_start;
+        let exact = complete && partition_results.iter().all(|(_, _, finished)| *finished);
+        stats.budget_exhausted = !exact;
+        let matched = partition_results
             .iter()
-            .enumerate()
-            .for_each(|(doc_id, score)| {
-                if threshold < *score {
-                    heap.push(search::Result {
-                        doc_id: (doc_id + k) as u32,
-                        score: *score,
-                    });
-                    heap.pop();
-                    threshold = heap.peek().unwrap().score;
-                }
-            });
+            .map(|(_, matched, _)| matched)
+            .sum();
+        let mut topk: Vec<search::Result> = partition_results
+            .into_iter()
+            .flat_map(|(topk, _, _)| topk)
+            .collect();
+        topk.sort_unstable();
+        topk.truncate(k);
+        topk.iter_mut().for_each(|result| result.score -= offset);
+        stats.topk = topk_start.elapsed();
+        (topk, matched, exact)
+    }
 
+    /// Explains the score of document `doc_id` for a query processed with `mode`
+    ///
+    /// Segments are processed according to the postings, term and segment budgets of `mode`. Time
+    /// limits and rank-safe termination are not simulated.
+    ///
+    /// # Errors
+    /// - fails if the document does not exist
+    pub fn explain(
+        &self,
+        tokens: &[Term],
+        mode: QueryMode,
+        k: usize,
+        doc_id: u32,
+    ) -> anyhow::Result<search::Explanation> {
+        if doc_id > self.max_doc_id {
+            anyhow::bail!("unknown document {}", doc_id);
+        }
+        let start = std::time::Instant::now();
+        let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
+            search::Scratch::from_index(self.max_level, self.max_term_weight, self.max_doc_id)
+        });
+        let total_postings = self.determine_impact_segments(&mut search_buf, tokens);
+        let mode = match mode {
+            QueryMode::Target(target) => {
+                self.target_budget(&search_buf, &Position::start(&search_buf), target)
+            }
+            mode => mode,
+        };
+        let limits = Limits::new(mode, start, total_postings, k);
+        let (selected, _) = select_groups(&search_buf.impacts, &search_buf.term_levels, &limits);
 
-        let mut threshold = heap.peek().unwrap().score;
-        let mut doc_id = init_heap_docs;
-        chunks
+        let mut score = 0;
+        let mut excluded = self.deleted.read().contains(doc_id);
+        let large_decode_buf = &mut search_buf.large_decode_buf;
+        let decode_buf = &mut search_buf.decode_buf;
+        let terms = tokens
             .iter()
-            .skip(init_heap_chunks)
-            .zip(accumulators.chunks(search::CHUNK_SIZE as usize).skip(init_heap_chunks))
-            .for_each(|(&chunk_max, scores)| {
-                if chunk_max > threshold {
-                    scores.iter().for_each(|&score| {
-                        if threshold < score {
-                            heap.push(search::Result {
-                                doc_id: doc_id as u32,
-                                score,
-                            });
-                            heap.pop();
-                            threshold = heap.peek().unwrap().score;
+            .enumerate()
+            .map(|(term, tok)| {
+                let segment = self
+                    .find_segment(term, tok, doc_id, large_decode_buf, decode_buf)
+                    .map(|(segment, position, impact)| {
+                        let processed = match tok.kind {
+                            TermKind::Positive => selected.iter().any(|group| {
+                                group.term == term
+                                    && group.segment == segment.segment
+                                    && group.bytes == segment.bytes
+                            }),
+                            TermKind::Negative | TermKind::Exclude => true,
+                        };
+                        let weighted_impact = segment.impact();
+                        match tok.kind {
+                            TermKind::Positive if processed => score += i64::from(weighted_impact),
+                            TermKind::Positive => {}
+                            TermKind::Negative => score -= i64::from(weighted_impact),
+                            TermKind::Exclude => excluded = true,
+                        }
+                        search::SegmentExplanation {
+                            index_segment: segment.segment,
+                            position,
+                            postings: segment.count(),
+                            impact,
+                            weighted_impact,
+                            processed,
                         }
-                        doc_id += 1;
                     });
-                } else {
-                    doc_id += search::CHUNK_SIZE;
+                search::TermExplanation {
+                    token: tok.token.clone(),
+                    kind: tok.kind,
+                    query_weight: tok.freq,
+                    segment,
+                }
+            })
+            .collect();
+        drop(selected);
+        self.search_bufs.lock().push(search_buf);
+        Ok(search::Explanation {
+            doc_id,
+            terms,
+            score,
+            excluded,
+        })
+    }
+
+    /// The impact segment of query term `term` holding document `doc_id`, its position among the
+    /// impact segments of the term and its unweighted impact
+    fn find_segment(
+        &self,
+        term: usize,
+        tok: &Term,
+        doc_id: u32,
+        large_decode_buf: &mut crate::compress::LargeBuffer,
+        decode_buf: &mut crate::compress::Buffer,
+    ) -> Option<(impact::Impact, usize, u16)> {
+        self.term_lists(&tok.token)
+            .filter(|&(_, doc_offset, _)| doc_id >= doc_offset)
+            .find_map(|(segment, doc_offset, list)| {
+                list.segments(tok.freq as u16)
+                    .enumerate()
+                    .find_map(|(position, impact)| {
+                        let impact = impact.in_segment(segment, doc_offset).of_term(term);
+                        let mut contains = false;
+                        impact.clone().for_each_doc::<Compressor>(
+                            self.segment_data(segment),
+                            large_decode_buf,
+                            decode_buf,
+                            |doc| contains |= doc == doc_id,
+                        );
+                        contains.then(|| (impact, position, list.impacts[position].impact))
+                    })
+            })
+    }
+
+    /// Reads static document priors used to break score ties
+    ///
+    /// Each line holds a collection docid and its prior. Among documents with equal scores, those
+    /// with higher priors rank first, and documents without a prior rank last. Remaining ties are
+    /// broken by lower internal docid.
+    ///
+    /// # Errors
+    /// - fails if the file can't be read or a prior can't be parsed
+    pub fn read_priors<P: AsRef<std::path::Path>>(&mut self, file_name: P) -> anyhow::Result<()> {
+        let mut names = Vec::new();
+        let mut priors = Vec::new();
+        let contents = std::fs::read_to_string(file_name)?;
+        for line in contents.lines() {
+            let mut parts = line.split_whitespace();
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(prior)) => {
+                    names.push(name);
+                    priors.push(prior.parse::<f32>()?);
                 }
+                (None, _) => continue,
+                _ => anyhow::bail!("invalid prior line '{}'", line),
+            }
+        }
+        let mut doc_priors: Vec<(u32, f32)> = names
+            .iter()
+            .zip(priors)
+            .filter_map(|(name, prior)| {
+                let doc_id = self.resolve_doc_ids(std::slice::from_ref(name)).pop()?;
+                Some((doc_id, prior))
+            })
+            .collect();
+        doc_priors.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut ranks = vec![u32::MAX; self.docmap.len()];
+        for (rank, (doc_id, _)) in doc_priors.into_iter().enumerate() {
+            ranks[doc_id as usize] = rank as u32;
+        }
+        self.prior_ranks = Some(ranks);
+        Ok(())
+    }
+
+    /// Tie-break keys of the documents that may appear in the results of a query
+    fn tie_keys<'a>(
+        &'a self,
+        excluded: &'a Bitmap,
+        filter: Option<&'a DocFilter>,
+    ) -> impl Fn(u32) -> Option<u64> + 'a {
+        let admits = self.admits(excluded, filter);
+        let ranks = self.prior_ranks.as_deref();
+        move |doc_id| {
+            let rank = ranks.map_or(0, |ranks| {
+                ranks.get(doc_id as usize).copied().unwrap_or(u32::MAX)
             });
+            admits(doc_id).then(|| u64::from(rank) << 32 | u64::from(doc_id))
+        }
+    }
+
+    /// Whether a document may appear in the results of a query
+    fn admits<'a>(
+        &'a self,
+        excluded: &'a Bitmap,
+        filter: Option<&'a DocFilter>,
+    ) -> impl Fn(u32) -> bool + 'a {
+        let deleted = self.deleted.read();
+        move |doc_id| {
+            !excluded.contains(doc_id)
+                && !deleted.contains(doc_id)
+                && filter.is_none_or(|filter| filter.admits(doc_id))
+        }
+    }
+
+    fn determine_topk_chunks(
+        &self,
+        data: &mut search::Scratch,
+        k: usize,
+        filter: Option<&DocFilter>,
+    ) -> (Vec<search::Result>, usize) {
+        let tie_key = self.tie_keys(&data.excluded, filter);
+        let heap = &mut data.heap;
+        let touched = if data.sparse {
+            Some(data.pages.touched())
+        } else {
+            None
+        };
+        let offset = data.offset;
+        let matched = fill_topk_heap(
+            heap,
+            &data.accumulators,
+            &data.chunk,
+            touched,
+            offset,
+            k,
+            tie_key,
+        );
         // only alloc happens here
         let mut result = Vec::with_capacity(heap.len());
-        while let Some(elem) = heap.pop() {
+        while let Some(mut elem) = heap.pop() {
+            elem.score -= offset;
             result.push(elem);
         }
-        result.into_iter().rev().collect()
+        result.reverse();
+        (result, matched)
     }
 
     pub fn query_fraction(
@@ -362,6 +1442,90 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
         query_id: Option<usize>,
         k: usize,
     ) -> search::Results {
+        self.query_fraction_filtered(tokens, rho, query_id, k, None)
+    }
+
+    /// Like `query_fraction` but only returns documents admitted by `filter`
+    pub fn query_fraction_filtered(
+        &self,
+        tokens: &[Term],
+        rho: f32,
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&DocFilter>,
+    ) -> search::Results {
+        self.query(tokens, QueryMode::Fraction(rho), query_id, k, filter)
+    }
+
+    pub fn query_fixed(
+        &self,
+        tokens: &[Term],
+        postings_budget: i64,
+        query_id: Option<usize>,
+        k: usize,
+    ) -> search::Results {
+        self.query_fixed_filtered(tokens, postings_budget, query_id, k, None)
+    }
+
+    /// Like `query_fixed` but only returns documents admitted by `filter`
+    pub fn query_fixed_filtered(
+        &self,
+        tokens: &[Term],
+        postings_budget: i64,
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&DocFilter>,
+    ) -> search::Results {
+        self.query(
+            tokens,
+            QueryMode::Fixed(postings_budget),
+            query_id,
+            k,
+            filter,
+        )
+    }
+
+    /// Processes postings only until the top-k documents can no longer change
+    pub fn query_rank_safe(
+        &self,
+        tokens: &[Term],
+        query_id: Option<usize>,
+        k: usize,
+    ) -> search::Results {
+        self.query(tokens, QueryMode::RankSafe, query_id, k, None)
+    }
+
+    /// Processes a query in the given mode, only returning documents admitted by `filter`
+    pub fn query(
+        &self,
+        tokens: &[Term],
+        mode: QueryMode,
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&DocFilter>,
+    ) -> search::Results {
+        self.query_cursor(tokens, query_id, k, filter).resume(mode)
+    }
+
+    /// Like `query` but splits the documents into up to `parallelism` ranges processed in parallel
+    ///
+    /// Each worker decodes the selected impact segments from their start up to the end of its
+    /// range and selects its own top-k, so decoding is repeated rather than split and the speedup
+    /// is limited to accumulation. Rank-safe queries and small collections are processed
+    /// sequentially.
+    pub fn query_parallel(
+        &self,
+        tokens: &[Term],
+        mode: QueryMode,
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&DocFilter>,
+        parallelism: usize,
+    ) -> search::Results {
+        let partitions = doc_partitions(self.max_doc_id as usize + 1, k, parallelism);
+        if partitions.len() < 2 || mode == QueryMode::RankSafe {
+            return self.query(tokens, mode, query_id, k, filter);
+        }
         let start = std::time::Instant::now();
 
         let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
@@ -369,40 +1533,241 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
         });
 
         let total_postings = self.determine_impact_segments(&mut search_buf, tokens);
-        let postings_budget = (total_postings as f32 * rho).ceil() as i64;
-        self.process_impact_segments(&mut search_buf, postings_budget);
-        let topk = self.determine_topk_chunks(&mut search_buf, k);
+        let mode = match mode {
+            QueryMode::Target(target) => {
+                self.target_budget(&search_buf, &Position::start(&search_buf), target)
+            }
+            mode => mode,
+        };
+        let limits = Limits::new(mode, start, total_postings, k);
+        let accumulation_start = std::time::Instant::now();
+        let mut stats = search::Stats {
+            total_postings,
+            unknown_terms: self.unknown_terms(tokens),
+            planning: accumulation_start - start,
+            ..search::Stats::default()
+        };
+        self.prepare_accumulators(&mut search_buf, false);
+        stats.accumulation = accumulation_start.elapsed();
+        let (topk, matched, exact) =
+            self.process_partitioned(&mut search_buf, &limits, &partitions, k, filter, &mut stats);
 
         self.search_bufs.lock().push(search_buf);
         search::Results {
             topk,
             took: start.elapsed(),
             qid: query_id.unwrap_or_default(),
+            exact,
+            matched,
+            stats,
         }
     }
 
-    pub fn query_fixed(
-        &self,
-        tokens: &[Term],
-        postings_budget: i64,
+    /// Processes a query on several indexes of the same documents and returns the top-k of the
+    /// weighted sums of their scores
+    ///
+    /// The impacts of each index are scaled by its weight and accumulated into shared
+    /// accumulators, so the combined scores are exact up to rounding. Postings, term and segment
+    /// budgets of the `mode` apply to each index separately. A deadline is shared: each index gets
+    /// an equal part of the time left when its turn comes. Rank-safe queries process all postings
+    /// and target latencies act as deadlines. Deleted documents and priors are those of the
+    /// first index.
+    ///
+    /// # Errors
+    /// - No indexes are given
+    /// - The indexes don't have the same documents in the same order
+    /// - A weight is negative or the weighted scores can exceed the accumulators
+    pub fn query_hybrid(
+        parts: &[HybridQuery<'_, Compressor>],
+        mode: QueryMode,
         query_id: Option<usize>,
         k: usize,
-    ) -> search::Results {
+        filter: Option<&DocFilter>,
+    ) -> anyhow::Result<search::Results> {
         let start = std::time::Instant::now();
+        let primary = hybrid_primary(parts)?;
+        // score bounds and cost models of one index say nothing about the combined scores
+        let mode = match mode {
+            QueryMode::RankSafe => QueryMode::Fraction(1.0),
+            QueryMode::Target(target) => QueryMode::Deadline(target),
+            mode => mode,
+        };
 
-        let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
-            search::Scratch::from_index(self.max_level, self.max_term_weight, self.max_doc_id)
-        });
+        let mut search_bufs: Vec<_> = parts
+            .iter()
+            .map(|part| {
+                let index = part.index;
+                index.search_bufs.lock().pop().unwrap_or_else(|| {
+                    search::Scratch::from_index(
+                        index.max_level,
+                        index.max_term_weight,
+                        index.max_doc_id,
+                    )
+                })
+            })
+            .collect();
+        let mut stats = search::Stats::default();
+        let mut total_postings = Vec::with_capacity(parts.len());
+        let mut offset: u32 = 0;
+        let mut max_score: u32 = 0;
+        for (part, search_buf) in parts.iter().zip(&mut search_bufs) {
+            let postings = part
+                .index
+                .determine_impact_segments(search_buf, part.tokens);
+            search_buf.weight_impacts(part.weight);
+            offset += u32::from(search_buf.offset);
+            max_score += search_buf.max_score();
+            total_postings.push(postings);
+            stats.total_postings += postings;
+            for term in part.index.unknown_terms(part.tokens) {
+                if !stats.unknown_terms.contains(&term) {
+                    stats.unknown_terms.push(term);
+                }
+            }
+        }
 
-        self.determine_impact_segments(&mut search_buf, tokens);
-        self.process_impact_segments(&mut search_buf, postings_budget);
-        let topk = self.determine_topk_chunks(&mut search_buf, k);
+        if max_score > u32::from(ScoreType::MAX) {
+            for (part, search_buf) in parts.iter().zip(search_bufs) {
+                part.index.search_bufs.lock().push(search_buf);
+            }
+            anyhow::bail!(
+                "the weighted scores of the hybrid query can reach {}, more than the accumulators \
+                 hold; lower the weights",
+                max_score
+            );
+        }
+        let headroom = u32::from(ScoreType::MAX) - max_score;
+        if offset > headroom {
+            tracing::warn!(
+                "penalties of the hybrid query are capped to fit the score accumulators"
+            );
+        }
 
-        self.search_bufs.lock().push(search_buf);
-        search::Results {
+        let accumulation_start = std::time::Instant::now();
+        stats.planning = accumulation_start - start;
+        let num_parts = parts.len() as u32;
+        let part_limits = |part: u32, postings| {
+            let mode = split_time_budget(mode, start, num_parts - part);
+            Limits::new(mode, std::time::Instant::now(), postings, k)
+        };
+        // every index accumulates into the accumulators of the first
+        let (shared, rest) = search_bufs.split_first_mut().expect("one index");
+        shared.offset = offset.min(headroom) as ScoreType;
+        primary.prepare_accumulators(shared, false);
+        let mut position = Position::start(shared);
+        let limits = part_limits(0, total_postings[0]);
+        let mut exact =
+            primary.process_impact_segments(shared, &mut position, limits, filter, &mut stats);
+        for (((part, search_buf), &postings), part_id) in parts[1..]
+            .iter()
+            .zip(rest)
+            .zip(&total_postings[1..])
+            .zip(1..)
+        {
+            search_buf.swap_accumulators(shared);
+            part.index.apply_penalties(search_buf);
+            let mut position = Position::start(search_buf);
+            let limits = part_limits(part_id, postings);
+            exact &= part.index.process_impact_segments(
+                search_buf,
+                &mut position,
+                limits,
+                filter,
+                &mut stats,
+            );
+            search_buf.swap_accumulators(shared);
+        }
+        stats.budget_exhausted = !exact;
+        let topk_start = std::time::Instant::now();
+        stats.accumulation = topk_start - accumulation_start;
+        let (topk, matched) = primary.determine_topk_chunks(shared, k, filter);
+        stats.topk = topk_start.elapsed();
+
+        for (part, search_buf) in parts.iter().zip(search_bufs) {
+            part.index.search_bufs.lock().push(search_buf);
+        }
+        Ok(search::Results {
             topk,
             took: start.elapsed(),
             qid: query_id.unwrap_or_default(),
+            exact,
+            matched,
+            stats,
+        })
+    }
+
+    /// Processes a query on several indexes of the same documents one index at a time and fuses
+    /// their top-k with `fusion`, each weighted by the weight of its index
+    ///
+    /// Budgets apply as in `query_hybrid`, except that target latencies are split like deadlines
+    /// and each index selects its budget with its own cost model.
+    ///
+    /// # Errors
+    /// - No indexes are given
+    /// - The indexes don't have the same documents in the same order
+    /// - A weight is negative
+    pub fn query_hybrid_fused(
+        parts: &[HybridQuery<'_, Compressor>],
+        fusion: Fusion,
+        mode: QueryMode,
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&DocFilter>,
+    ) -> anyhow::Result<search::Results> {
+        let start = std::time::Instant::now();
+        hybrid_primary(parts)?;
+        let num_parts = parts.len() as u32;
+        let results: Vec<_> = parts
+            .iter()
+            .zip(0..)
+            .map(|(part, part_id)| {
+                let mode = split_time_budget(mode, start, num_parts - part_id);
+                part.index.query(part.tokens, mode, query_id, k, filter)
+            })
+            .collect();
+        let weights: Vec<f64> = parts.iter().map(|part| f64::from(part.weight)).collect();
+        let mut fused = fusion.fuse_results(&results, &weights, k);
+        fused.took = start.elapsed();
+        Ok(fused)
+    }
+
+    /// Starts a query whose processing is driven by calls to `QueryCursor::resume`
+    ///
+    /// The search buffers of the query stay with the cursor until it is dropped.
+    pub fn query_cursor<'a>(
+        &'a self,
+        tokens: &[Term],
+        query_id: Option<usize>,
+        k: usize,
+        filter: Option<&'a DocFilter>,
+    ) -> QueryCursor<'a, Compressor> {
+        let start = std::time::Instant::now();
+        let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
+            search::Scratch::from_index(self.max_level, self.max_term_weight, self.max_doc_id)
+        });
+        let total_postings = self.determine_impact_segments(&mut search_buf, tokens);
+        let sparse = self.prefers_sparse(&search_buf, total_postings);
+        let accumulation_start = std::time::Instant::now();
+        self.prepare_accumulators(&mut search_buf, sparse);
+        let position = Position::start(&search_buf);
+        let stats = search::Stats {
+            total_postings,
+            unknown_terms: self.unknown_terms(tokens),
+            planning: accumulation_start - start,
+            accumulation: accumulation_start.elapsed(),
+            ..search::Stats::default()
+        };
+        QueryCursor {
+            index: self,
+            search_buf: Some(search_buf),
+            position,
+            total_postings,
+            k,
+            filter,
+            qid: query_id.unwrap_or_default(),
+            took: start.elapsed(),
+            exact: false,
+            stats,
         }
     }
 
@@ -411,21 +1776,29 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
         let mut search_buf = self.search_bufs.lock().pop().unwrap_or_else(|| {
             search::Scratch::from_index(self.max_level, self.max_term_weight, self.max_doc_id)
         });
-        self.determine_impact_segments(&mut search_buf, tokens);
-        self.process_impact_segments(&mut search_buf, postings_budget);
+        let total_postings = self.determine_impact_segments(&mut search_buf, tokens);
+        let sparse = self.prefers_sparse(&search_buf, total_postings);
+        self.prepare_accumulators(&mut search_buf, sparse);
+        let mut position = Position::start(&search_buf);
+        self.process_impact_segments(
+            &mut search_buf,
+            &mut position,
+            Limits::postings(postings_budget),
+            None,
+            &mut search::Stats::default(),
+        );
         self.search_bufs.lock().push(search_buf);
     }
 
     fn quantize_and_encode(
-        max_score: f32,
-        quant_bits: u32,
+        quantizer: Option<score::LinearQuantizer>,
         num_plists: usize,
         ciff_reader: &ciff::Reader,
         scorer: impl score::Scorer,
         doclen: &[f64],
         num_docs: u32,
+        doc_freq: impl Fn(&str, u32) -> u32 + Sync,
     ) -> Vec<(String, (list::List, Vec<u8>))> {
-        let quantizer = score::LinearQuantizer::new(max_score, quant_bits);
         let pb_quantizer = util::progress_bar("quantize/encode postings", num_plists);
         (0..num_plists)
             .into_par_iter()
@@ -433,7 +1806,7 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
             .map(|idx| {
                 let plist = ciff_reader.postings_list(idx);
                 let mut posting_map: BTreeMap<Reverse<u16>, Vec<u32>> = BTreeMap::new();
-                let list_len = plist.postings.len() as u32;
+                let list_len = doc_freq(&plist.term, plist.postings.len() as u32);
                 let mut doc_id: u32 = 0;
                 for ciff::Posting { docid, tf } in &plist.postings {
                     doc_id += *docid as u32;
@@ -443,10 +1816,10 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
                         doclen[doc_id as usize] as f32,
                         num_docs,
                     );
-                    let impact = if scorer.needs_quantization() {
-                        u16::try_from(quantizer.quantize(freq)).expect("impact < u16::max")
-                    } else {
-                        freq as u16
+                    let impact = match quantizer {
+                        Some(quantizer) => u16::try_from(quantizer.quantize_clamped(freq))
+                            .expect("impact < u16::max"),
+                        None => freq as u16,
                     };
                     let entry = posting_map.entry(Reverse(impact)).or_default();
                     entry.push(doc_id);
@@ -462,6 +1835,447 @@ impl<Compressor: crate::compress::Compressor> Index<Compressor> {
     }
 }
 
+/// One index of a hybrid query, with the query terms for it and the weight of its scores
+#[derive(Debug)]
+pub struct HybridQuery<'a, C: crate::compress::Compressor> {
+    pub index: &'a Index<C>,
+    pub tokens: &'a [Term],
+    pub weight: f32,
+}
+
+/// A query that was processed up to some budget and can be resumed with more
+#[derive(Debug)]
+pub struct QueryCursor<'a, C: crate::compress::Compressor> {
+    index: &'a Index<C>,
+    search_buf: Option<search::Scratch>,
+    position: Position,
+    total_postings: usize,
+    k: usize,
+    filter: Option<&'a DocFilter>,
+    qid: usize,
+    took: std::time::Duration,
+    exact: bool,
+    stats: search::Stats,
+}
+
+impl<'a, C: crate::compress::Compressor> QueryCursor<'a, C> {
+    /// Continues processing where the previous call stopped and returns the updated top-k
+    ///
+    /// The `mode` is an additional budget: fractions are relative to all postings of the query
+    /// and deadlines start with this call. `took` of the results adds up all calls.
+    pub fn resume(&mut self, mode: QueryMode) -> search::Results {
+        let start = std::time::Instant::now();
+        let index = self.index;
+        let search_buf = self.search_buf.as_mut().expect("search buffers");
+        let stats = &mut self.stats;
+        let mode = match mode {
+            QueryMode::Target(target) => index.target_budget(search_buf, &self.position, target),
+            mode => mode,
+        };
+        let limits = Limits::new(mode, start, self.total_postings, self.k);
+        let accumulation_start = std::time::Instant::now();
+        stats.planning += accumulation_start - start;
+        let exact = index.process_impact_segments(
+            search_buf,
+            &mut self.position,
+            limits,
+            self.filter,
+            stats,
+        );
+        self.exact |= exact;
+        stats.budget_exhausted = !exact;
+        let topk_start = std::time::Instant::now();
+        stats.accumulation += topk_start - accumulation_start;
+        let (topk, matched) = index.determine_topk_chunks(search_buf, self.k, self.filter);
+        stats.topk += topk_start.elapsed();
+        self.took += start.elapsed();
+        search::Results {
+            topk,
+            took: self.took,
+            qid: self.qid,
+            exact: self.exact,
+            matched,
+            stats: stats.clone(),
+        }
+    }
+
+    /// Whether all postings of the query have been processed
+    pub fn is_finished(&self) -> bool {
+        self.position.levels == 0 && self.position.skipped.is_empty()
+    }
+}
+
+impl<C: crate::compress::Compressor> Drop for QueryCursor<'_, C> {
+    fn drop(&mut self) {
+        if let Some(search_buf) = self.search_buf.take() {
+            self.index.search_bufs.lock().push(search_buf);
+        }
+    }
+}
+
+/// The next impact group to process, with impact levels processed from the highest down
+#[derive(Debug)]
+struct Position {
+    // levels not processed completely yet
+    levels: usize,
+    group: usize,
+    // (level, group) of passed groups that a term budget skipped
+    skipped: Vec<(usize, usize)>,
+}
+
+impl Position {
+    fn start(data: &search::Scratch) -> Self {
+        Position {
+            levels: data.impacts.len(),
+            group: 0,
+            skipped: Vec::new(),
+        }
+    }
+
+    /// The impact groups left to process, in processing order
+    fn remaining_groups<'a>(
+        &self,
+        data: &'a search::Scratch,
+    ) -> impl Iterator<Item = &'a impact::Impact> + 'a {
+        let group = self.group;
+        let skipped = self.skipped.clone().into_iter();
+        let skipped = skipped.map(move |(level, group)| &data.impacts[level][group]);
+        skipped.chain(
+            data.impacts[..self.levels]
+                .iter()
+                .rev()
+                .enumerate()
+                .flat_map(move |(idx, groups)| groups[if idx == 0 { group } else { 0 }..].iter()),
+        )
+    }
+}
+
+/// When processing of the impact segments of a query stops
+#[derive(Debug)]
+struct Limits {
+    postings_budget: i64,
+    deadline: Option<std::time::Instant>,
+    rank_safe_k: Option<usize>,
+    term_budget: Option<i64>,
+    max_groups: usize,
+    min_impact: ScoreType,
+}
+
+impl Limits {
+    fn postings(postings_budget: i64) -> Self {
+        Limits {
+            postings_budget,
+            deadline: None,
+            rank_safe_k: None,
+            term_budget: None,
+            max_groups: usize::MAX,
+            min_impact: 0,
+        }
+    }
+
+    fn new(mode: QueryMode, start: std::time::Instant, total_postings: usize, k: usize) -> Self {
+        match mode {
+            QueryMode::Fraction(rho) => Self::postings((total_postings as f32 * rho).ceil() as i64),
+            QueryMode::Fixed(postings_budget) => Self::postings(postings_budget),
+            QueryMode::RankSafe => Limits {
+                rank_safe_k: Some(k),
+                ..Self::postings(total_postings as i64)
+            },
+            QueryMode::Deadline(duration) | QueryMode::Target(duration) => Limits {
+                deadline: Some(start + duration),
+                ..Self::postings(total_postings as i64)
+            },
+            QueryMode::TermCap(term_budget) => Limits {
+                term_budget: Some(term_budget),
+                ..Self::postings(total_postings as i64)
+            },
+            QueryMode::Segments(max_groups) => Limits {
+                max_groups,
+                ..Self::postings(total_postings as i64)
+            },
+            QueryMode::MinImpact(min_impact) => Limits {
+                min_impact,
+                ..Self::postings(total_postings as i64)
+            },
+        }
+    }
+}
+
+/// The impact groups a query processes under the postings, term, segment and impact limits, and
+/// whether these are all of its groups
+fn select_groups<'a>(
+    impacts: &'a [Vec<impact::Impact>],
+    term_levels: &[(usize, ScoreType)],
+    limits: &Limits,
+) -> (Vec<&'a impact::Impact>, bool) {
+    let mut postings_budget = limits.postings_budget;
+    let num_terms = term_levels.last().map_or(0, |&(term, _)| term + 1);
+    let mut term_postings: smallvec::SmallVec<[i64; 16]> = smallvec::smallvec![0; num_terms];
+    let mut selected = Vec::new();
+    let mut complete = true;
+    for (level, impact_groups) in impacts.iter().enumerate().rev() {
+        if impact_groups.is_empty() {
+            continue;
+        }
+        if (level as ScoreType) < limits.min_impact {
+            return (selected, false);
+        }
+        for impact_group in impact_groups {
+            if postings_budget < 0 || selected.len() >= limits.max_groups {
+                return (selected, false);
+            }
+            let term = impact_group.term;
+            if limits
+                .term_budget
+                .is_some_and(|budget| term_postings[term] > budget)
+            {
+                complete = false;
+                continue;
+            }
+            let num_postings = i64::from(impact_group.count());
+            postings_budget -= num_postings;
+            term_postings[term] += num_postings;
+            selected.push(impact_group);
+        }
+    }
+    (selected, complete)
+}
+
+/// The index of the first part of a hybrid query, after checking that all indexes share its
+/// documents and that the weights are valid
+fn hybrid_primary<'a, C: crate::compress::Compressor>(
+    parts: &[HybridQuery<'a, C>],
+) -> anyhow::Result<&'a Index<C>> {
+    let Some(primary) = parts.first().map(|part| part.index) else {
+        anyhow::bail!("a hybrid query needs at least one index");
+    };
+    for part in parts {
+        if part.index.docmap_hash() != primary.docmap_hash() {
+            anyhow::bail!("the indexes of a hybrid query must share their documents");
+        }
+        if !(part.weight.is_finite() && part.weight >= 0.0) {
+            anyhow::bail!("invalid hybrid query weight {}", part.weight);
+        }
+    }
+    Ok(primary)
+}
+
+/// The mode of the next of `parts_left` indexes of a hybrid query started at `start`, which gets
+/// an equal part of the time left
+fn split_time_budget(mode: QueryMode, start: std::time::Instant, parts_left: u32) -> QueryMode {
+    let time_left =
+        |budget: std::time::Duration| budget.saturating_sub(start.elapsed()) / parts_left;
+    match mode {
+        QueryMode::Deadline(deadline) => QueryMode::Deadline(time_left(deadline)),
+        QueryMode::Target(target) => QueryMode::Target(time_left(target)),
+        mode => mode,
+    }
+}
+
+/// Splits the documents into up to `parallelism` chunk aligned ranges
+///
+/// Every range holds at least `k` documents so that each can fill its own top-k.
+fn doc_partitions(num_docs: usize, k: usize, parallelism: usize) -> Vec<std::ops::Range<usize>> {
+    let round_up = |n: usize| n.div_ceil(search::CHUNK_SIZE).max(1) * search::CHUNK_SIZE;
+    let part_len = round_up(num_docs.div_ceil(parallelism.max(1))).max(round_up(k));
+    let mut partitions: Vec<_> = (0..num_docs)
+        .step_by(part_len)
+        .map(|first| first..(first + part_len).min(num_docs))
+        .collect();
+    // a short last range joins the previous one
+    if partitions.len() > 1 && partitions.last().is_some_and(|last| last.len() < part_len) {
+        let last = partitions.pop().expect("partitions");
+        partitions.last_mut().expect("partitions").end = last.end;
+    }
+    partitions
+}
+
+/// Adds `impact` to the accumulators of the documents in `chunk` and updates the chunk maxima
+///
+/// With `SPARSE`, the accumulator page of each document is initialized when first touched.
+#[inline(always)]
+fn accumulate<const SPARSE: bool>(
+    chunk: &[u32],
+    doc_offset: usize,
+    impact: ScoreType,
+    accumulators: &mut [ScoreType],
+    chunks: &mut [ScoreType],
+    pages: &mut search::DirtyPages,
+    offset: ScoreType,
+) {
+    chunk.iter().cloned().for_each(|doc_id| {
+        let doc_id = doc_id as usize + doc_offset;
+        if SPARSE {
+            pages.touch(doc_id, accumulators, chunks, offset);
+        }
+        let chunk_id = doc_id >> search::CHUNK_SHIFT;
+        let accum = unsafe { accumulators.get_unchecked_mut(doc_id) };
+        *accum += impact;
+        let chnk = unsafe { chunks.get_unchecked_mut(chunk_id) };
+        *chnk = (*chnk).max(*accum);
+    });
+}
+
+/// Fills the heap with the `k` best documents scoring above `offset`
+///
+/// `tie_key` gives the tie-break key of documents that may be returned. With `touched_pages`, only
+/// the accumulators of those pages are considered. Returns the number of returnable documents
+/// scoring above `offset`.
+fn fill_topk_heap(
+    heap: &mut std::collections::BinaryHeap<search::Result>,
+    accumulators: &[ScoreType],
+    chunks: &[ScoreType],
+    touched_pages: Option<&[u32]>,
+    offset: ScoreType,
+    k: usize,
+    tie_key: impl Fn(u32) -> Option<u64>,
+) -> usize {
+    heap.clear();
+    let mut matched = 0;
+    let mut scan_chunk = |chunk: usize| {
+        // the chunk maxima only grow with positive impacts, so they bound every match
+        if chunks[chunk] <= offset {
+            return;
+        }
+        let start = chunk << search::CHUNK_SHIFT;
+        let end = (start + search::CHUNK_SIZE).min(accumulators.len());
+        for (doc_id, &score) in (start..end).zip(&accumulators[start..end]) {
+            let doc_id = doc_id as u32;
+            if score <= offset {
+                continue;
+            }
+            let Some(tie) = tie_key(doc_id) else {
+                continue;
+            };
+            matched += 1;
+            let result = search::Result {
+                doc_id,
+                score,
+                tie,
+                real_score: None,
+            };
+            if heap.len() < k {
+                heap.push(result);
+            } else if heap.peek().is_some_and(|worst| result < *worst) {
+                heap.push(result);
+                heap.pop();
+            }
+        }
+    };
+    match touched_pages {
+        Some(pages) => pages.iter().for_each(|&page| scan_chunk(page as usize)),
+        None => (0..chunks.len()).for_each(scan_chunk),
+    }
+    matched
+}
+
+/// Upper bound on the score any document can still gain once all impacts above `level` are processed
+///
+/// Each term contributes at most its largest impact segment not above `level`.
+fn remaining_upper_bound(term_levels: &[(usize, ScoreType)], level: ScoreType) -> u32 {
+    term_levels
+        .chunk_by(|a, b| a.0 == b.0)
+        .map(|levels| {
+            levels
+                .iter()
+                .map(|&(_, impact)| impact)
+                .filter(|&impact| impact <= level)
+                .max()
+                .map_or(0, u32::from)
+        })
+        .sum()
+}
+
+/// Reads the docmap and the normalized document lengths of a CIFF file
+pub(crate) fn read_doc_records(
+    ciff_reader: &ciff::Reader,
+) -> anyhow::Result<(Vec<String>, Vec<f64>)> {
+    let pb_docmap = util::progress_bar("determine docmap", ciff_reader.header.num_docs as usize);
+    let avg_doclen = ciff_reader.header.average_doclength;
+    let mut docmap = Vec::new();
+    let mut doclen = Vec::new();
+    let mut max_doc_id = 0;
+    for doc_record in ciff_reader.doc_record_iter().progress_with(pb_docmap) {
+        docmap.push(doc_record.collection_docid);
+        doclen.push(f64::from(doc_record.doclength) / avg_doclen);
+        max_doc_id = max_doc_id.max(doc_record.docid as u32);
+    }
+    if docmap.len() != (max_doc_id + 1) as usize {
+        anyhow::bail!("Document map length does not match the maximum document identifier. Is your CIFF file corrupt?");
+    }
+    Ok((docmap, doclen))
+}
+
+/// Concatenates the encoded lists of all terms into a single buffer
+fn concatenate_lists(encoded_data: Vec<(String, (list::List, Vec<u8>))>) -> (Vocab, Vec<u8>) {
+    let pb_write = util::progress_bar("create index", encoded_data.len());
+    let mut vocab: Vocab = HashMap::default();
+    let mut list_data =
+        Vec::with_capacity(encoded_data.iter().map(|(_, (_, data))| data.len()).sum());
+    for (term, (mut list, term_data)) in encoded_data.into_iter().progress_with(pb_write) {
+        list.start_byte_offset = list_data.len();
+        vocab.insert(term, list);
+        list_data.extend_from_slice(&term_data);
+    }
+    (vocab, list_data)
+}
+
+fn shift_doc_ids(postings: &mut [(u16, Vec<u32>)], doc_offset: u32) {
+    postings
+        .iter_mut()
+        .flat_map(|(_, docs)| docs.iter_mut())
+        .for_each(|doc_id| *doc_id += doc_offset);
+}
+
+/// Maps the impacts of postings quantized by `from` to those of the same scores quantized by `to`
+fn requantize(
+    postings: Vec<(u16, Vec<u32>)>,
+    from: score::LinearQuantizer,
+    to: score::LinearQuantizer,
+) -> Vec<(u16, Vec<u32>)> {
+    let mut posting_map: BTreeMap<Reverse<u16>, Vec<u32>> = BTreeMap::new();
+    for (impact, docs) in postings {
+        let impact = to.quantize_clamped(from.dequantize(u32::from(impact)));
+        let impact = u16::try_from(impact).expect("impact < u16::max");
+        posting_map.entry(Reverse(impact)).or_default().extend(docs);
+    }
+    posting_map
+        .into_iter()
+        .map(|(impact, mut docs)| {
+            // levels that map to the same impact are merged
+            docs.sort_unstable();
+            (impact.0, docs)
+        })
+        .collect()
+}
+
+/// Merges the postings of a term from parts covering increasing document ranges
+fn merge_postings(parts: impl Iterator<Item = Vec<(u16, Vec<u32>)>>) -> Vec<(u16, Vec<u32>)> {
+    let mut posting_map: BTreeMap<Reverse<u16>, Vec<u32>> = BTreeMap::new();
+    for (impact, docs) in parts.flatten() {
+        posting_map.entry(Reverse(impact)).or_default().extend(docs);
+    }
+    posting_map
+        .into_iter()
+        .map(|(impact, docs)| (impact.0, docs))
+        .collect()
+}
+
+/// Removes a sidecar file written for a previous index, if there is one
+fn remove_if_exists<P: AsRef<std::path::Path>>(file_name: P) -> std::io::Result<()> {
+    match std::fs::remove_file(file_name) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// The deletions of an index are stored next to it with an extra extension
+fn deletions_path<P: AsRef<std::path::Path>>(index_file_name: P) -> std::path::PathBuf {
+    let mut file_name = index_file_name.as_ref().as_os_str().to_owned();
+    file_name.push(".deleted");
+    std::path::PathBuf::from(file_name)
+}
+
 fn determine_max_score(
     num_plists: usize,
     ciff_reader: &ciff::Reader,
@@ -496,3 +2310,964 @@ fn determine_max_score(
         .expect("max_score")
         .into_inner()
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::query::Query;
+
+    pub(crate) type TestIndex = Index<crate::compress::SimdBPandStreamVbyte>;
+    pub(crate) type TermPostings = (&'static str, Vec<(u16, Vec<u32>)>);
+
+    /// Builds a small in-memory index from `(term, [(impact, docs)])` postings
+    pub(crate) fn index_from_postings(num_docs: usize, postings: &[TermPostings]) -> TestIndex {
+        let docmap = (0..num_docs).map(|d| format!("doc{}", d)).collect();
+        let num_postings = postings
+            .iter()
+            .flat_map(|(_, lists)| lists.iter().map(|(_, docs)| docs.len()))
+            .sum();
+        let encoded_data = postings
+            .iter()
+            .map(|(term, lists)| {
+                (
+                    term.to_string(),
+                    list::List::encode::<crate::compress::SimdBPandStreamVbyte>(lists),
+                )
+            })
+            .collect();
+        let scoring = Scoring {
+            scorer: score::Identity::new().into(),
+            avg_doc_len: 1.0,
+        };
+        Index::from_encoded_lists(docmap, encoded_data, num_postings, None, scoring, 1)
+    }
+
+    /// Writes a CIFF file with `num_docs` documents of length 10 and the `(docid, tf)` postings
+    pub(crate) fn write_ciff(
+        path: &std::path::Path,
+        prefix: &str,
+        num_docs: usize,
+        postings: &[(&str, Vec<(i32, i32)>)],
+    ) {
+        use prost::Message;
+        let mut buf = Vec::new();
+        ciff::format::Header {
+            version: 1,
+            num_postings_lists: postings.len() as i32,
+            num_docs: num_docs as i32,
+            total_postings_lists: postings.len() as i32,
+            total_docs: num_docs as i32,
+            total_terms_in_collection: 10 * num_docs as i64,
+            average_doclength: 10.0,
+            description: String::new(),
+        }
+        .encode_length_delimited(&mut buf)
+        .unwrap();
+        for (term, plist) in postings {
+            let mut prev = 0;
+            ciff::PostingsList {
+                term: term.to_string(),
+                df: plist.len() as i64,
+                cf: plist.iter().map(|&(_, tf)| i64::from(tf)).sum(),
+                postings: plist
+                    .iter()
+                    .map(|&(docid, tf)| {
+                        let gap = docid - prev;
+                        prev = docid;
+                        ciff::Posting { docid: gap, tf }
+                    })
+                    .collect(),
+            }
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+        }
+        for docid in 0..num_docs {
+            ciff::DocRecord {
+                docid: docid as i32,
+                collection_docid: format!("{}{}", prefix, docid),
+                doclength: 10,
+            }
+            .encode_length_delimited(&mut buf)
+            .unwrap();
+        }
+        std::fs::write(path, buf).unwrap();
+    }
+
+    pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ioqp-{}-{}", std::process::id(), name))
+    }
+
+    pub(crate) fn animal_index() -> TestIndex {
+        index_from_postings(
+            200,
+            &[
+                ("cat", vec![(3, vec![1, 2, 5]), (1, vec![0, 3])]),
+                ("dog", vec![(2, vec![2, 3])]),
+                ("bird", vec![(2, vec![5, 150])]),
+            ],
+        )
+    }
+
+    pub(crate) fn scores(results: &search::Results) -> Vec<(u32, ScoreType)> {
+        results.topk.iter().map(|r| (r.doc_id, r.score)).collect()
+    }
+
+    #[test]
+    fn exclusion_terms_remove_documents() {
+        let index = animal_index();
+        let query = "1:cat -dog".parse::<Query>().unwrap();
+        let results = index.query_fraction(&query.tokens, 1.0, None, 3);
+        let mut topk = scores(&results);
+        topk[..2].sort_unstable();
+        assert_eq!(&topk[..2], &[(1, 3), (5, 3)]);
+        assert!(topk.iter().all(|&(doc_id, _)| doc_id != 2 && doc_id != 3));
+
+        // the next query reuses the search buffers and excludes nothing
+        let query = "1:cat".parse::<Query>().unwrap();
+        let results = index.query_fraction(&query.tokens, 1.0, None, 3);
+        assert!(scores(&results).contains(&(2, 3)));
+    }
+
+    #[test]
+    fn negative_terms_demote_documents() {
+        let index = animal_index();
+        let query = "1:cat ~bird".parse::<Query>().unwrap();
+        let results = index.query_fraction(&query.tokens, 1.0, None, 5);
+        let mut topk = scores(&results);
+        topk[..2].sort_unstable();
+        assert_eq!(&topk[..2], &[(1, 3), (2, 3)]);
+        assert!(topk[2..].contains(&(5, 1)));
+        assert!(topk.iter().all(|&(doc_id, _)| doc_id != 150));
+    }
+
+    #[test]
+    fn filter_restricts_results() {
+        let index = animal_index();
+        let query = "1:cat".parse::<Query>().unwrap();
+
+        let allow = DocFilter::allow_doc_ids(
+            &index.resolve_doc_ids(&["doc0", "doc2", "nodoc"]),
+            index.max_doc_id() + 1,
+        );
+        let results = index.query_fixed_filtered(&query.tokens, i64::MAX, None, 3, Some(&allow));
+        assert_eq!(scores(&results), vec![(2, 3), (0, 1)]);
+
+        let deny = DocFilter::deny_doc_ids(&[1, 2], index.max_doc_id() + 1);
+        let results = index.query_fraction_filtered(&query.tokens, 1.0, None, 2, Some(&deny));
+        assert_eq!(results.topk[0].doc_id, 5);
+        assert!(results.topk.iter().all(|r| r.doc_id != 1 && r.doc_id != 2));
+    }
+
+    #[test]
+    fn index_files_carry_a_format_version() {
+        let index_file = temp_path("versioned.idx");
+        animal_index().write_to_file(&index_file).unwrap();
+        assert!(TestIndex::read_from_file(&index_file).is_ok());
+
+        let mut data = std::fs::read(&index_file).unwrap();
+        data[4..8].copy_from_slice(&(INDEX_HEADER.version + 1).to_le_bytes());
+        std::fs::write(&index_file, &data).unwrap();
+        let err = TestIndex::read_from_file(&index_file).unwrap_err();
+        assert!(err.to_string().contains("format version"));
+
+        // files written before versioning start with the bincode encoded docmap
+        std::fs::write(&index_file, &data[8..]).unwrap();
+        let err = TestIndex::read_from_file(&index_file).unwrap_err();
+        assert!(err.to_string().contains("not an ioqp index"));
+        std::fs::remove_file(index_file).unwrap();
+    }
+
+    #[test]
+    fn deleted_documents_are_excluded_and_compacted() {
+        let mut index = animal_index();
+        index.set_cost_model(cost::CostModel {
+            base_micros: 1.0,
+            micros_per_group: 0.0,
+            micros_per_posting: 0.0,
+        });
+        assert_eq!(index.delete_collection_doc_ids(&["doc1", "doc5"]), 2);
+        assert_eq!(index.delete_doc_ids(&[1, 1000]), 0);
+        let query = "1:cat".parse::<Query>().unwrap();
+        let results = index.query_fraction(&query.tokens, 1.0, None, 2);
+        assert_eq!(scores(&results), vec![(2, 3), (0, 1)]);
+
+        let index_file = std::env::temp_dir().join(format!("ioqp-{}.idx", std::process::id()));
+        index.write_to_file(&index_file).unwrap();
+        let reloaded = TestIndex::read_from_file(&index_file).unwrap();
+        assert_eq!(reloaded.num_deleted(), 2);
+        assert!(reloaded.cost_model().is_some());
+        // the deletions replace the previous file once they are written completely
+        reloaded.write_deletions(&index_file).unwrap();
+        let mut temp_file_name = deletions_path(&index_file).into_os_string();
+        temp_file_name.push(".tmp");
+        assert!(!std::path::Path::new(&temp_file_name).exists());
+        assert_eq!(
+            TestIndex::read_from_file(&index_file)
+                .unwrap()
+                .num_deleted(),
+            2
+        );
+
+        // compacting in place drops the deletions of the old docids
+        let compacted = reloaded.compact();
+        compacted.write_to_file(&index_file).unwrap();
+        assert!(!deletions_path(&index_file).exists());
+        assert!(!cost::sidecar_path(&index_file).exists());
+        let compacted = TestIndex::read_from_file(&index_file).unwrap();
+        std::fs::remove_file(&index_file).unwrap();
+        assert_eq!(compacted.num_deleted(), 0);
+        assert_eq!(compacted.docmap().len(), 198);
+        assert_eq!(compacted.num_postings(), 6);
+        let results = compacted.query_fraction(&query.tokens, 1.0, None, 2);
+        let docmap = compacted.docmap();
+        let topk: Vec<_> = results
+            .topk
+            .iter()
+            .map(|r| (docmap[r.doc_id as usize].as_str(), r.score))
+            .collect();
+        assert_eq!(topk, vec![("doc2", 3), ("doc0", 1)]);
+    }
+
+    #[test]
+    fn rank_safe_stops_once_topk_is_fixed() {
+        let index = index_from_postings(
+            200,
+            &[
+                (
+                    "cat",
+                    vec![(9, vec![1]), (8, vec![2]), (1, (3..150).collect())],
+                ),
+                ("dog", vec![(1, vec![1, 2])]),
+            ],
+        );
+        let query = "1:cat dog".parse::<Query>().unwrap();
+        let exhaustive = index.query_fraction(&query.tokens, 1.0, None, 2);
+        assert!(exhaustive.exact);
+        assert_eq!(scores(&exhaustive), vec![(1, 10), (2, 9)]);
+        // the level-1 postings can't lift any other document into the top-2
+        let safe = index.query_rank_safe(&query.tokens, None, 2);
+        assert!(safe.exact);
+        assert_eq!(scores(&safe), vec![(1, 9), (2, 8)]);
+        assert!(!index.query_fixed(&query.tokens, 1, None, 2).exact);
+    }
+
+    #[test]
+    fn deadline_returns_best_topk_so_far() {
+        let index = animal_index();
+        let query = "1:cat dog".parse::<Query>().unwrap();
+        let expired = QueryMode::Deadline(std::time::Duration::ZERO);
+        let results = index.query(&query.tokens, expired, None, 3, None);
+        assert!(!results.exact);
+        // nothing was accumulated, and documents without a score don't match
+        assert!(results.topk.is_empty());
+        assert_eq!(results.matched, 0);
+
+        let generous = QueryMode::Deadline(std::time::Duration::from_secs(60));
+        let results = index.query(&query.tokens, generous, None, 3, None);
+        let exhaustive = index.query_fraction(&query.tokens, 1.0, None, 3);
+        assert!(results.exact);
+        assert_eq!(scores(&results), scores(&exhaustive));
+    }
+
+    #[test]
+    fn resumed_cursor_reaches_exhaustive_topk() {
+        let index = animal_index();
+        let query = "1:cat dog bird".parse::<Query>().unwrap();
+        let exhaustive = index.query_fraction(&query.tokens, 1.0, Some(1), 3);
+
+        let mut cursor = index.query_cursor(&query.tokens, Some(1), 3, None);
+        let first = cursor.resume(QueryMode::Fixed(0));
+        assert!(!first.exact);
+        let mut topk = scores(&first);
+        topk.sort_unstable();
+        assert_eq!(topk, vec![(1, 3), (2, 3), (5, 3)]);
+        let mut steps = 1;
+        while !cursor.is_finished() {
+            cursor.resume(QueryMode::Fixed(0));
+            steps += 1;
+        }
+        // one impact group per call: cat has two, dog and bird share one level
+        assert_eq!(steps, 4);
+        let last = cursor.resume(QueryMode::Fixed(0));
+        assert!(last.exact);
+        assert_eq!(last.qid, 1);
+        assert_eq!(scores(&last), scores(&exhaustive));
+    }
+
+    #[test]
+    fn resumed_cursor_revisits_groups_skipped_by_term_cap() {
+        let index = animal_index();
+        let query = "1:cat dog bird".parse::<Query>().unwrap();
+        let exhaustive = index.query_fraction(&query.tokens, 1.0, Some(1), 10);
+
+        let mut cursor = index.query_cursor(&query.tokens, Some(1), 10, None);
+        // the second cat segment is skipped
+        let capped = cursor.resume(QueryMode::TermCap(0));
+        assert!(!capped.exact);
+        assert!(!cursor.is_finished());
+        let resumed = cursor.resume(QueryMode::Fixed(0));
+        assert!(resumed.exact);
+        assert!(cursor.is_finished());
+        assert_eq!(resumed.stats.processed_segments, 4);
+        assert_eq!(scores(&resumed), scores(&exhaustive));
+    }
+
+    #[test]
+    fn target_latency_uses_cost_model_budget() {
+        let mut index = animal_index();
+        let query = "1:cat dog bird".parse::<Query>().unwrap();
+        assert_eq!(index.impact_group_counts(&query.tokens), vec![3, 2, 2, 2]);
+        let target = QueryMode::Target(std::time::Duration::from_secs(60));
+        // without a cost model the target acts as a deadline
+        assert!(index.query(&query.tokens, target, None, 3, None).exact);
+
+        index.set_cost_model(cost::CostModel {
+            base_micros: 0.0,
+            micros_per_group: 0.0,
+            micros_per_posting: 1.0,
+        });
+        let target = QueryMode::Target(std::time::Duration::from_micros(4));
+        let results = index.query(&query.tokens, target, None, 3, None);
+        assert!(!results.exact);
+        let mut topk = scores(&results);
+        topk.sort_unstable();
+        assert_eq!(topk, vec![(1, 3), (2, 3), (5, 3)]);
+    }
+
+    #[test]
+    fn budget_policies_limit_processed_segments() {
+        let index = animal_index();
+        let query = "1:cat dog bird".parse::<Query>().unwrap();
+        let ranking = |mode| {
+            let results = index.query(&query.tokens, mode, None, 10, None);
+            let mut topk: Vec<_> = scores(&results)
+                .into_iter()
+                .filter(|&(_, score)| score > 0)
+                .collect();
+            topk.sort_unstable();
+            (topk, results.exact)
+        };
+        // segments in processing order: cat 3, dog 2, bird 2, cat 1
+        assert_eq!(
+            ranking(QueryMode::TermCap(0)),
+            (vec![(1, 3), (2, 5), (3, 2), (5, 5), (150, 2)], false)
+        );
+        assert_eq!(
+            ranking(QueryMode::Segments(2)),
+            (vec![(1, 3), (2, 5), (3, 2), (5, 3)], false)
+        );
+        assert_eq!(
+            ranking(QueryMode::MinImpact(2)),
+            (vec![(1, 3), (2, 5), (3, 2), (5, 5), (150, 2)], false)
+        );
+        let exhaustive = (vec![(0, 1), (1, 3), (2, 5), (3, 3), (5, 5), (150, 2)], true);
+        assert_eq!(ranking(QueryMode::TermCap(3)), exhaustive);
+        assert_eq!(ranking(QueryMode::Segments(4)), exhaustive);
+        assert_eq!(ranking(QueryMode::MinImpact(1)), exhaustive);
+    }
+
+    #[test]
+    fn doc_partitions_are_chunk_aligned_and_hold_k_docs() {
+        assert_eq!(
+            doc_partitions(1000, 10, 4),
+            vec![0..256, 256..512, 512..1000]
+        );
+        assert_eq!(doc_partitions(1000, 300, 4), vec![0..384, 384..1000]);
+        assert_eq!(doc_partitions(200, 10, 8), vec![0..200]);
+        assert_eq!(doc_partitions(1000, 10, 1), vec![0..1000]);
+    }
+
+    #[test]
+    fn parallel_query_matches_sequential() {
+        let levels = |num_levels: u16, modulo: u32| {
+            (1..=num_levels)
+                .rev()
+                .map(|level| {
+                    let docs = (0..1000)
+                        .filter(|d| d % modulo == u32::from(level))
+                        .collect();
+                    (level, docs)
+                })
+                .collect::<Vec<_>>()
+        };
+        let index = index_from_postings(
+            1000,
+            &[
+                ("a", levels(5, 6)),
+                ("b", levels(3, 7)),
+                ("c", levels(4, 13)),
+            ],
+        );
+        let query = "1:a b c".parse::<Query>().unwrap();
+        let filter = DocFilter::deny_doc_ids(&(0..500).collect::<Vec<_>>(), index.max_doc_id() + 1);
+        let modes = [
+            QueryMode::Fraction(1.0),
+            QueryMode::Fixed(500),
+            QueryMode::TermCap(100),
+            QueryMode::Segments(3),
+            QueryMode::MinImpact(3),
+            QueryMode::RankSafe,
+        ];
+        for mode in modes {
+            for filter in [None, Some(&filter)] {
+                let sequential = index.query(&query.tokens, mode, None, 20, filter);
+                let parallel = index.query_parallel(&query.tokens, mode, None, 20, filter, 3);
+                let sorted_scores = |results: &search::Results| {
+                    let mut scores: Vec<_> = results.topk.iter().map(|r| r.score).collect();
+                    scores.sort_unstable();
+                    scores
+                };
+                assert_eq!(sorted_scores(&parallel), sorted_scores(&sequential));
+                assert_eq!(parallel.exact, sequential.exact);
+                assert!(parallel
+                    .topk
+                    .iter()
+                    .all(|r| filter.is_none_or(|f| f.admits(r.doc_id))));
+            }
+        }
+    }
+
+    #[test]
+    fn results_hold_only_matching_documents() {
+        let index = index_from_postings(
+            10,
+            &[
+                ("a", vec![(2, vec![3, 7]), (1, vec![1, 8])]),
+                ("b", vec![(1, vec![8, 9])]),
+                ("c", vec![(1, vec![1])]),
+            ],
+        );
+        let query = "1:a b ~c".parse::<Query>().unwrap();
+        for parallelism in [1, 2] {
+            let results = index.query_parallel(
+                &query.tokens,
+                QueryMode::Fraction(1.0),
+                None,
+                50,
+                None,
+                parallelism,
+            );
+            let mut topk: Vec<_> = results.topk.iter().map(|r| (r.doc_id, r.score)).collect();
+            topk.sort_unstable();
+            assert_eq!(topk, vec![(3, 2), (7, 2), (8, 2), (9, 1)]);
+            assert_eq!(results.matched, 4);
+        }
+
+        let results = index.query(&query.tokens, QueryMode::Fraction(1.0), None, 2, None);
+        assert_eq!(results.topk.len(), 2);
+        assert!(results.topk.iter().all(|r| r.score == 2));
+        assert_eq!(results.matched, 4);
+    }
+
+    #[test]
+    fn ties_are_broken_by_docid_or_prior() {
+        let mut index = index_from_postings(
+            1000,
+            &[
+                ("a", vec![(2, vec![500, 900]), (1, (0..1000).collect())]),
+                ("b", vec![(1, vec![3, 700])]),
+            ],
+        );
+        let query = "1:a b".parse::<Query>().unwrap();
+        let ranking = |index: &TestIndex, parallelism| {
+            let results = index.query_parallel(
+                &query.tokens,
+                QueryMode::Fraction(1.0),
+                None,
+                6,
+                None,
+                parallelism,
+            );
+            results.topk.iter().map(|r| r.doc_id).collect::<Vec<_>>()
+        };
+        assert_eq!(ranking(&index, 1), vec![500, 900, 3, 700, 0, 1]);
+        assert_eq!(ranking(&index, 3), ranking(&index, 1));
+
+        let priors = temp_path("priors");
+        std::fs::write(&priors, "doc900 0.5\ndoc700 2.0\ndoc42 1.0\nunknown 9\n").unwrap();
+        index.read_priors(&priors).unwrap();
+        std::fs::remove_file(&priors).unwrap();
+        assert_eq!(ranking(&index, 1), vec![900, 500, 700, 3, 42, 0]);
+        assert_eq!(ranking(&index, 3), ranking(&index, 1));
+    }
+
+    #[test]
+    fn real_scores_undo_quantization_and_weight_rescaling() {
+        let mut index = index_from_postings(10, &[("a", vec![(64, vec![1]), (32, vec![2])])]);
+        let query = "1:a".parse::<Query>().unwrap();
+        let mut results = index.query(&query.tokens, QueryMode::Fraction(1.0), None, 10, None);
+        index.add_real_scores(&mut results, 1.0);
+        let real_scores: Vec<_> = results.topk.iter().map(|r| r.real_score).collect();
+        assert_eq!(real_scores, vec![Some(64.0), Some(32.0)]);
+
+        index.quantizer = Some(score::LinearQuantizer::new(8.0, 8));
+        index.add_real_scores(&mut results, 2.0);
+        let real_scores: Vec<_> = results.topk.iter().map(|r| r.real_score).collect();
+        assert_eq!(real_scores, vec![Some(4.0), Some(2.0)]);
+    }
+
+    #[test]
+    fn explain_reports_segments_and_budget_cutoffs() {
+        let index = index_from_postings(
+            10,
+            &[
+                ("a", vec![(3, vec![1, 5]), (1, vec![2, 7])]),
+                ("b", vec![(2, vec![5, 9])]),
+                ("c", vec![(1, vec![5])]),
+                ("d", vec![(1, vec![7])]),
+            ],
+        );
+        let query = "1:a a b ~c -d".parse::<Query>().unwrap();
+        let explanation = index
+            .explain(&query.tokens, QueryMode::Segments(1), 10, 5)
+            .unwrap();
+        let segment = |token: &str| {
+            let term = explanation.terms.iter().find(|t| t.token == token).unwrap();
+            term.segment.as_ref().map(|s| {
+                (
+                    term.query_weight,
+                    s.position,
+                    s.impact,
+                    s.weighted_impact,
+                    s.processed,
+                )
+            })
+        };
+        assert_eq!(segment("a"), Some((2, 0, 3, 6, true)));
+        assert_eq!(segment("b"), Some((1, 0, 2, 2, false)));
+        assert_eq!(segment("c"), Some((1, 0, 1, 1, true)));
+        assert_eq!(segment("d"), None);
+        assert_eq!(explanation.score, 5);
+        assert!(!explanation.excluded);
+
+        let exhaustive = QueryMode::Fraction(1.0);
+        let explanation = index.explain(&query.tokens, exhaustive, 10, 5).unwrap();
+        let results = index.query(&query.tokens, exhaustive, None, 10, None);
+        let result = results.topk.iter().find(|r| r.doc_id == 5).unwrap();
+        assert_eq!(explanation.score, i64::from(result.score));
+
+        assert!(
+            index
+                .explain(&query.tokens, exhaustive, 10, 7)
+                .unwrap()
+                .excluded
+        );
+        assert!(index.explain(&query.tokens, exhaustive, 10, 10).is_err());
+    }
+
+    #[test]
+    fn hybrid_queries_add_weighted_scores_of_each_index() {
+        let bm25 = index_from_postings(
+            300,
+            &[
+                ("cat", vec![(3, vec![1, 2, 5]), (1, vec![0, 3])]),
+                ("dog", vec![(2, vec![2, 3])]),
+            ],
+        );
+        let splade = index_from_postings(
+            300,
+            &[
+                ("cat", vec![(4, vec![5, 200]), (2, vec![1])]),
+                ("feline", vec![(5, vec![200])]),
+                ("bird", vec![(6, vec![2])]),
+            ],
+        );
+        let bm25_query = "1:cat dog zzz".parse::<Query>().unwrap();
+        let splade_query = "1:cat feline ~bird".parse::<Query>().unwrap();
+        fn part<'a>(
+            index: &'a TestIndex,
+            query: &'a Query,
+            weight: f32,
+        ) -> HybridQuery<'a, crate::compress::SimdBPandStreamVbyte> {
+            HybridQuery {
+                index,
+                tokens: &query.tokens,
+                weight,
+            }
+        }
+
+        let single = TestIndex::query_hybrid(
+            &[part(&bm25, &bm25_query, 1.0)],
+            QueryMode::Fraction(1.0),
+            None,
+            10,
+            None,
+        )
+        .unwrap();
+        let plain = bm25.query_fraction(&bm25_query.tokens, 1.0, None, 10);
+        assert_eq!(scores(&single), scores(&plain));
+
+        // the penalty of bird pushes doc 2 below zero
+        let results = TestIndex::query_hybrid(
+            &[
+                part(&bm25, &bm25_query, 1.0),
+                part(&splade, &splade_query, 2.0),
+            ],
+            QueryMode::RankSafe,
+            Some(1),
+            10,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            scores(&results),
+            vec![(200, 18), (5, 11), (1, 7), (3, 3), (0, 1)]
+        );
+        assert_eq!(results.matched, 5);
+        assert!(results.exact);
+        assert_eq!(results.stats.unknown_terms, vec!["zzz".to_string()]);
+
+        let other_docs = index_from_postings(200, &[("cat", vec![(1, vec![0])])]);
+        let mismatched = TestIndex::query_hybrid(
+            &[
+                part(&bm25, &bm25_query, 1.0),
+                part(&other_docs, &bm25_query, 1.0),
+            ],
+            QueryMode::Fraction(1.0),
+            None,
+            10,
+            None,
+        );
+        assert!(mismatched.is_err());
+        let mut renamed = index_from_postings(300, &[("cat", vec![(1, vec![0])])]);
+        renamed.docmap.swap(0, 1);
+        let mismatched = TestIndex::query_hybrid(
+            &[
+                part(&bm25, &bm25_query, 1.0),
+                part(&renamed, &bm25_query, 1.0),
+            ],
+            QueryMode::Fraction(1.0),
+            None,
+            10,
+            None,
+        );
+        assert!(mismatched.is_err());
+
+        // cat alone could reach 3 * 30000 in the weighted scores
+        let overflowing = TestIndex::query_hybrid(
+            &[part(&bm25, &bm25_query, 30000.0)],
+            QueryMode::Fraction(1.0),
+            None,
+            10,
+            None,
+        );
+        assert!(overflowing.is_err());
+
+        // fused rankings count as much as the weight of their index
+        let rrf = Fusion::Rrf { k: 60.0 };
+        let fused_top = |bm25_weight, splade_weight| {
+            let parts = [
+                part(&bm25, &bm25_query, bm25_weight),
+                part(&splade, &splade_query, splade_weight),
+            ];
+            let mode = QueryMode::Deadline(std::time::Duration::from_secs(60));
+            let results = TestIndex::query_hybrid_fused(&parts, rrf, mode, Some(1), 2, None);
+            let results = results.unwrap();
+            assert!(results.exact);
+            results.topk.iter().map(|r| r.doc_id).collect::<Vec<_>>()
+        };
+        assert_eq!(fused_top(1.0, 0.0)[0], 2);
+        assert_eq!(fused_top(0.0, 1.0), vec![200, 5]);
+        let mismatched = TestIndex::query_hybrid_fused(
+            &[
+                part(&bm25, &bm25_query, 1.0),
+                part(&renamed, &bm25_query, 1.0),
+            ],
+            rrf,
+            QueryMode::Fraction(1.0),
+            None,
+            10,
+            None,
+        );
+        assert!(mismatched.is_err());
+    }
+
+    #[test]
+    fn time_budgets_are_split_between_hybrid_parts() {
+        let minute = std::time::Duration::from_secs(60);
+        let start = std::time::Instant::now();
+        let QueryMode::Target(part) = split_time_budget(QueryMode::Target(minute), start, 3) else {
+            panic!("target stays a target");
+        };
+        assert!(part <= minute / 3 && part > minute / 4);
+        let started = start - minute;
+        let expired = split_time_budget(QueryMode::Deadline(minute), started, 2);
+        assert_eq!(expired, QueryMode::Deadline(std::time::Duration::ZERO));
+        let fraction = QueryMode::Fraction(0.5);
+        assert_eq!(split_time_budget(fraction, start, 2), fraction);
+    }
+
+    #[test]
+    fn results_report_query_stats() {
+        let index = index_from_postings(
+            1000,
+            &[
+                (
+                    "a",
+                    vec![(3, (0..300).collect()), (1, (300..1000).collect())],
+                ),
+                ("b", vec![(2, (0..1000).step_by(2).collect())]),
+            ],
+        );
+        let query = "1:a b zzz".parse::<Query>().unwrap();
+        for parallelism in [1, 3] {
+            let stats = |mode| {
+                let results =
+                    index.query_parallel(&query.tokens, mode, None, 10, None, parallelism);
+                let json = serde_json::to_string(&results).unwrap();
+                serde_json::from_str::<search::Results>(&json)
+                    .unwrap()
+                    .stats
+            };
+            let partial = stats(QueryMode::Fixed(100));
+            assert_eq!(partial.total_postings, 1500);
+            assert_eq!(partial.processed_postings, 300);
+            assert_eq!(partial.processed_segments, 1);
+            assert!(partial.budget_exhausted);
+            assert_eq!(partial.unknown_terms, vec!["zzz".to_string()]);
+
+            let full = stats(QueryMode::Fraction(1.0));
+            assert_eq!(full.processed_postings, 1500);
+            assert_eq!(full.processed_segments, 3);
+            assert!(!full.budget_exhausted);
+        }
+    }
+
+    #[test]
+    fn sparse_accumulators_match_dense() {
+        let index = index_from_postings(
+            100_000,
+            &[
+                ("a", vec![(3, vec![10, 50_000, 99_999]), (1, vec![11, 12])]),
+                ("b", vec![(2, vec![50_000, 70_000])]),
+                ("c", vec![(1, vec![10, 11])]),
+                ("d", vec![(1, vec![12])]),
+            ],
+        );
+        let query = "1:a b ~c -d".parse::<Query>().unwrap();
+        let cursor = index.query_cursor(&query.tokens, None, 10, None);
+        assert!(cursor.search_buf.as_ref().unwrap().sparse);
+        drop(cursor);
+
+        let run = |sparse: bool, limits: Limits| {
+            let mut data = search::Scratch::from_index(
+                index.max_level,
+                index.max_term_weight,
+                index.max_doc_id,
+            );
+            index.determine_impact_segments(&mut data, &query.tokens);
+            index.prepare_accumulators(&mut data, sparse);
+            let mut position = Position::start(&data);
+            let stats = &mut search::Stats::default();
+            let exact =
+                index.process_impact_segments(&mut data, &mut position, limits, None, stats);
+            let mut matches: Vec<_> = index
+                .determine_topk_chunks(&mut data, 10, None)
+                .0
+                .into_iter()
+                .map(|r| (r.doc_id, r.score))
+                .collect();
+            matches.sort_unstable_by_key(|&(doc_id, score)| (std::cmp::Reverse(score), doc_id));
+            (matches, exact)
+        };
+        let exhaustive = || Limits::postings(i64::MAX);
+        let (sparse, _) = run(true, exhaustive());
+        assert_eq!(sparse, vec![(50_000, 5), (99_999, 3), (10, 2), (70_000, 2)]);
+        assert_eq!(run(false, exhaustive()).0, sparse);
+
+        let rank_safe = || Limits {
+            rank_safe_k: Some(2),
+            ..Limits::postings(i64::MAX)
+        };
+        assert_eq!(run(true, rank_safe()), run(false, rank_safe()));
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn rank_safe_matches_exhaustive_topk(postings: Vec<(u8, u16, u8)>, k: u8) -> bool {
+        let k = usize::from(k % 10) + 1;
+        let mut lists: [BTreeMap<Reverse<u16>, Vec<u32>>; 3] = Default::default();
+        let mut seen = HashSet::new();
+        for (term, doc_id, impact) in postings {
+            let (term, doc_id) = (usize::from(term % 3), u32::from(doc_id % 300));
+            if seen.insert((term, doc_id)) {
+                let impact = u16::from(impact % 8) + 1;
+                lists[term].entry(Reverse(impact)).or_default().push(doc_id);
+            }
+        }
+        let postings: Vec<TermPostings> = ["a", "b", "c"]
+            .iter()
+            .copied()
+            .zip(lists)
+            .filter(|(_, list)| !list.is_empty())
+            .map(|(term, list)| {
+                let list = list
+                    .into_iter()
+                    .map(|(impact, mut docs)| {
+                        docs.sort_unstable();
+                        (impact.0, docs)
+                    })
+                    .collect();
+                (term, list)
+            })
+            .collect();
+        if postings.is_empty() {
+            return true;
+        }
+        let index = index_from_postings(300, &postings);
+        let query = "1:a b c".parse::<Query>().unwrap();
+        let doc_ids = |results: &search::Results| {
+            let mut doc_ids: Vec<u32> = results.topk.iter().map(|r| r.doc_id).collect();
+            doc_ids.sort_unstable();
+            doc_ids
+        };
+        let safe = index.query_rank_safe(&query.tokens, None, k);
+        let exhaustive = index.query_fraction(&query.tokens, 1.0, None, k);
+        safe.exact && doc_ids(&safe) == doc_ids(&exhaustive)
+    }
+
+    #[test]
+    fn merged_indexes_concatenate_documents() {
+        let first = index_from_postings(
+            3,
+            &[
+                ("cat", vec![(3, vec![1]), (1, vec![0, 2])]),
+                ("dog", vec![(2, vec![2])]),
+            ],
+        );
+        let second = index_from_postings(
+            2,
+            &[
+                ("cat", vec![(3, vec![0]), (2, vec![1])]),
+                ("bird", vec![(1, vec![1])]),
+            ],
+        );
+        second.delete_doc_ids(&[1]);
+        let merged = TestIndex::merge(&[first, second]).unwrap();
+        assert_eq!(merged.docmap().len(), 5);
+        assert_eq!(merged.docmap()[3], "doc0");
+        assert_eq!(merged.num_postings(), 7);
+        assert_eq!(merged.num_deleted(), 1);
+        assert_eq!(
+            merged.decode_term("cat"),
+            vec![(3, vec![1, 3]), (2, vec![4]), (1, vec![0, 2])]
+        );
+        assert_eq!(merged.decode_term("bird"), vec![(1, vec![4])]);
+
+        let quantized = |global_max, quant_bits, impact| {
+            let mut index = index_from_postings(1, &[("cat", vec![(impact, vec![0])])]);
+            index.quantizer = Some(score::LinearQuantizer::new(global_max, quant_bits));
+            index
+        };
+        // a score of 1.0 quantizes to 128 with a global max of 2.0 and to 64 with one of 4.0
+        let merged = TestIndex::merge(&[quantized(2.0, 8, 128), quantized(4.0, 8, 200)]).unwrap();
+        assert_eq!(merged.quantizer().unwrap().global_max(), 4.0);
+        assert_eq!(
+            merged.decode_term("cat"),
+            vec![(200, vec![1]), (64, vec![0])]
+        );
+        assert!(TestIndex::merge(&[quantized(2.0, 8, 1), quantized(2.0, 7, 1)]).is_err());
+        assert!(TestIndex::merge(&[quantized(2.0, 8, 1), animal_index()]).is_err());
+        let mut bm25 = quantized(2.0, 8, 1);
+        bm25.scoring.scorer = score::BM25::new(0.9, 0.4).into();
+        assert!(TestIndex::merge(&[quantized(2.0, 8, 1), bm25]).is_err());
+        assert!(TestIndex::merge(&[]).is_err());
+    }
+
+    #[test]
+    fn indexes_without_postings_are_compacted_and_merged() {
+        let index = animal_index();
+        let all_docs: Vec<u32> = (0..=index.max_doc_id() as u32).collect();
+        index.delete_doc_ids(&all_docs);
+        let compacted = index.compact();
+        assert!(compacted.docmap().is_empty());
+        assert_eq!(compacted.num_postings(), 0);
+        let query = "1:cat".parse::<Query>().unwrap();
+        assert!(compacted
+            .query_fraction(&query.tokens, 1.0, None, 3)
+            .topk
+            .is_empty());
+
+        let empty = TestIndex::merge(&[compacted, index.compact()]).unwrap();
+        assert_eq!(empty.num_postings(), 0);
+        let merged = TestIndex::merge(&[empty, index]).unwrap();
+        assert_eq!(merged.num_deleted(), merged.docmap().len());
+        assert!(merged
+            .query_fraction(&query.tokens, 1.0, None, 3)
+            .topk
+            .is_empty());
+    }
+
+    #[test]
+    fn appended_segments_are_searched_and_merged() {
+        let base_ciff = temp_path("base.ciff");
+        let append_ciff = temp_path("append.ciff");
+        let append_more_ciff = temp_path("append-more.ciff");
+        write_ciff(
+            &base_ciff,
+            "a",
+            130,
+            &[("cat", vec![(0, 2), (2, 1)]), ("dog", vec![(1, 3)])],
+        );
+        write_ciff(
+            &append_ciff,
+            "b",
+            130,
+            &[("cat", vec![(1, 50)]), ("fish", vec![(0, 1)])],
+        );
+        write_ciff(
+            &append_more_ciff,
+            "c",
+            3,
+            &[("dog", vec![(0, 3)]), ("fish", vec![(2, 4)])],
+        );
+
+        let mut index =
+            TestIndex::from_ciff_file(&base_ciff, 8, score::BM25::new(0.9, 0.4)).unwrap();
+        let quantizer = index.quantizer().unwrap();
+        assert_eq!(quantizer.quant_bits(), 8);
+        index.append_ciff_file(&append_ciff).unwrap();
+        index.append_ciff_file(&append_more_ciff).unwrap();
+        std::fs::remove_file(&base_ciff).unwrap();
+        std::fs::remove_file(&append_ciff).unwrap();
+        std::fs::remove_file(&append_more_ciff).unwrap();
+        assert_eq!(index.num_segments(), 2);
+        assert_eq!(index.docmap().len(), 263);
+        assert_eq!(index.num_postings(), 7);
+
+        let ranking = |index: &TestIndex, query: &str| {
+            let query = query.parse::<Query>().unwrap();
+            let results = index.query_fraction(&query.tokens, 1.0, None, 3);
+            results
+                .topk
+                .iter()
+                .filter(|r| r.score > 0)
+                .map(|r| (index.docmap()[r.doc_id as usize].clone(), r.score))
+                .collect::<Vec<_>>()
+        };
+        let cat = ranking(&index, "1:cat");
+        assert_eq!(cat.len(), 3);
+        // scores above the global max of the base index are clamped
+        assert_eq!(cat[0], ("b1".to_string(), 1 << quantizer.quant_bits()));
+        let fish = ranking(&index, "1:fish -cat");
+        assert_eq!(fish.len(), 2);
+        // both fish postings are scored against the 130 documents of the base index
+        assert_eq!(fish[0].0, "c2");
+        // scored with the statistics of the base index rather than those of the three documents
+        let dog = ranking(&index, "1:dog");
+        assert_eq!(dog.len(), 2);
+        assert_eq!(dog[0].1, dog[1].1);
+
+        assert_eq!(index.merge_small_segments(5), 2);
+        assert_eq!(index.merge_small_segments(200), 1);
+        assert_eq!(ranking(&index, "1:cat"), cat);
+        assert_eq!(ranking(&index, "1:fish -cat"), fish);
+
+        index.delete_collection_doc_ids(&["a0"]);
+        let compacted = index.compact();
+        assert_eq!(compacted.num_segments(), 0);
+        assert_eq!(compacted.num_postings(), 6);
+        let remaining: Vec<_> = cat.into_iter().filter(|(name, _)| name != "a0").collect();
+        assert_eq!(ranking(&compacted, "1:cat"), remaining);
+        assert_eq!(ranking(&compacted, "1:fish -cat"), fish);
+    }
+}
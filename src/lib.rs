@@ -1,9 +1,14 @@
 #![warn(missing_debug_implementations, rust_2018_idioms)]
-#![feature(stdarch_x86_avx512)]
 
 //mod ciff;
+mod bitmap;
 pub mod ciff;
 pub mod compress;
+pub mod cost;
+pub mod eval;
+mod filter;
+pub mod forward;
+pub mod fusion;
 pub mod impact;
 mod index;
 mod list;
@@ -13,10 +18,16 @@ pub mod score;
 mod search;
 pub mod util;
 
+pub use bitmap::Bitmap;
 pub use compress::SimdBPandStreamVbyte;
 pub use compress::Uncompressed;
+pub use filter::DocFilter;
+pub use index::HybridQuery;
 pub use index::Index;
+pub use index::QueryCursor;
 pub use range::Byte;
+pub use search::Explanation;
+pub use search::QueryMode;
 pub use search::Results;
 
 // // Configurable: The data type for accumulating scores.
@@ -1,4 +1,5 @@
 use crate::impact;
+use crate::range::Byte;
 
 #[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct List {
@@ -27,12 +28,47 @@ impl List {
             output,
         )
     }
+
+    /// Iterates the impact segments of the list, scaling each impact by `query_weight`
+    pub fn segments(&self, query_weight: u16) -> impl Iterator<Item = impact::Impact> + '_ {
+        let mut start = self.start_byte_offset;
+        self.impacts.iter().map(move |ti| {
+            let stop = start + ti.bytes as usize;
+            let segment = impact::Impact::from_encoded_slice_weighted(
+                *ti,
+                Byte::new(start, stop),
+                query_weight,
+            );
+            start = stop;
+            segment
+        })
+    }
+
+    /// Decodes the list back into `(impact, docs)` pairs, the inverse of `encode`
+    #[must_use]
+    pub fn decode<Compressor: crate::compress::Compressor>(
+        &self,
+        list_data: &[u8],
+    ) -> Vec<(u16, Vec<u32>)> {
+        let mut large_decode_buf = [0; crate::compress::LARGE_BLOCK_LEN];
+        let mut decode_buf = [0; crate::compress::BLOCK_LEN];
+        self.segments(1)
+            .map(|mut segment| {
+                let mut docs = Vec::with_capacity(segment.count() as usize);
+                segment.for_each_doc::<Compressor>(
+                    list_data,
+                    &mut large_decode_buf,
+                    &mut decode_buf,
+                    |doc_id| docs.push(doc_id),
+                );
+                (segment.impact(), docs)
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::range::Byte;
-
     use super::*;
 
     #[quickcheck_macros::quickcheck]
@@ -83,6 +119,18 @@ mod tests {
         total_size == encoded_data.len()
     }
 
+    #[quickcheck_macros::quickcheck]
+    fn decode_recovers_input(impact_lists: Vec<crate::impact::tests::ImpactList>) -> bool {
+        let input: Vec<(u16, Vec<u32>)> = impact_lists
+            .into_iter()
+            .map(|il| (il.impact, il.docs))
+            .collect();
+
+        let (encoded_list, encoded_data) =
+            List::encode::<crate::compress::SimdBPandStreamVbyte>(&input);
+        encoded_list.decode::<crate::compress::SimdBPandStreamVbyte>(&encoded_data) == input
+    }
+
     #[quickcheck_macros::quickcheck]
     fn successfully_decode_many_impactlists_content(
         impact_lists: Vec<crate::impact::tests::ImpactList>,
@@ -120,7 +168,7 @@ mod tests {
                         }
                     }
                 }
-                if doc_iter.next() != None {
+                if doc_iter.next().is_some() {
                     all_good = false;
                 }
                 cur_offset = stop;
@@ -5,27 +5,90 @@ use std::io::BufRead;
 
 pub const MAX_TERM_WEIGHT: usize = 32;
 
+/// Query token prefix marking a term whose documents are removed from the results
+pub const EXCLUDE_PREFIX: char = '-';
+/// Query token prefix marking a term whose documents are demoted by its weight
+pub const NEGATIVE_PREFIX: char = '~';
+/// Query token prefix keeping the rest of the token as is, e.g. `\-lrb-` for the term `-lrb-`
+pub const ESCAPE_PREFIX: char = '\\';
+
+/// How the postings of a query term contribute to document scores
+#[derive(
+    Copy,
+    Clone,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+)]
+pub enum TermKind {
+    /// Impacts are added to the score of each document
+    #[default]
+    Positive,
+    /// Impacts are subtracted from the score of each document
+    Negative,
+    /// Documents containing the term never appear in the results
+    Exclude,
+}
+
+impl TermKind {
+    /// Splits a raw query token into the term kind and the actual token
+    #[must_use]
+    pub fn from_token(token: &str) -> (TermKind, &str) {
+        let mut chars = token.chars();
+        match chars.next() {
+            Some(EXCLUDE_PREFIX) if !chars.as_str().is_empty() => {
+                (TermKind::Exclude, chars.as_str())
+            }
+            Some(NEGATIVE_PREFIX) if !chars.as_str().is_empty() => {
+                (TermKind::Negative, chars.as_str())
+            }
+            Some(ESCAPE_PREFIX) if !chars.as_str().is_empty() => {
+                (TermKind::Positive, chars.as_str())
+            }
+            _ => (TermKind::Positive, token),
+        }
+    }
+}
+
 #[derive(Eq, Clone, serde::Serialize, serde::Deserialize, Debug)]
 pub struct Term {
     pub token: String,
     pub freq: u32,
+    #[serde(default)]
+    pub kind: TermKind,
 }
 
 impl std::fmt::Display for Term {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "(tok:{}, freq:{})", &self.token, self.freq)
+        match self.kind {
+            TermKind::Positive => write!(f, "(tok:{}, freq:{})", self.token, self.freq),
+            kind => write!(
+                f,
+                "(tok:{}, freq:{}, kind:{:?})",
+                self.token, self.freq, kind
+            ),
+        }
     }
 }
 
 impl PartialEq for Term {
     fn eq(&self, other: &Self) -> bool {
-        self.token == other.token
+        self.token == other.token && self.kind == other.kind
     }
 }
 
 impl Ord for Term {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.token.cmp(&self.token)
+        other
+            .token
+            .cmp(&self.token)
+            .then_with(|| self.kind.cmp(&other.kind))
     }
 }
 
@@ -35,12 +98,21 @@ impl PartialOrd for Term {
     }
 }
 
-#[derive(Eq, serde::Serialize, serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Query {
     pub id: usize,
     pub tokens: Vec<Term>,
+    /// Factor that turns the rescaled term weights back into the original ones
+    #[serde(default = "unit_weight_scale")]
+    pub weight_scale: f32,
+}
+
+fn unit_weight_scale() -> f32 {
+    1.0
 }
 
+impl Eq for Query {}
+
 impl PartialEq for Query {
     fn eq(&self, other: &Self) -> bool {
         self.id == other.id && self.tokens == other.tokens
@@ -53,16 +125,21 @@ impl std::str::FromStr for Query {
         let parts: Vec<&str> = s.splitn(2, ":").collect();
         let id = parts[0].parse::<usize>()?;
         let terms: Vec<String> = parts[1].split_whitespace().map(ToOwned::to_owned).collect();
-        let mut token_freqs: HashMap<String, u32> = HashMap::new();
+        let mut token_freqs: HashMap<(String, TermKind), u32> = HashMap::new();
         for t in &terms {
-            *token_freqs.entry(t.to_string()).or_insert(0) += 1;
+            let (kind, token) = TermKind::from_token(t);
+            *token_freqs.entry((token.to_string(), kind)).or_insert(0) += 1;
         }
         let mut tokens = Vec::new();
-        for (token, freq) in token_freqs {
-            tokens.push(Term { token, freq });
+        for ((token, kind), freq) in token_freqs {
+            tokens.push(Term { token, freq, kind });
         }
         tokens.sort();
-        Ok(Query { id, tokens })
+        Ok(Query {
+            id,
+            tokens,
+            weight_scale: 1.0,
+        })
     }
 }
 
@@ -72,15 +149,14 @@ impl Query {
     /// # Panics
     /// Panics if there are no tokens in the query
     #[must_use]
-    pub fn with_rescale(id: usize, mut tokens: Vec<Term>, max_weight: usize) -> Self {
-        let max_tok_weight = tokens.iter().map(|p| p.freq).max().unwrap() as usize;
-        if max_tok_weight <= max_weight {
-            return Self { id, tokens };
-        }
-        for i in &mut tokens {
-            i.freq = (max_weight as f32 * (i.freq as f32) / (max_tok_weight as f32)).ceil() as u32;
-        }
-        Self { id, tokens }
+    pub fn with_rescale(id: usize, tokens: Vec<Term>, max_weight: usize) -> Self {
+        let mut query = Self {
+            id,
+            tokens,
+            weight_scale: 1.0,
+        };
+        query.rescale(max_weight);
+        query
     }
 
     // Rescales query terms in-place uniformly into [1, `max_weight`]
@@ -94,6 +170,7 @@ impl Query {
                 i.freq =
                     (max_weight as f32 * (i.freq as f32) / (max_tok_weight as f32)).ceil() as u32;
             }
+            self.weight_scale *= max_tok_weight as f32 / max_weight as f32;
         }
     }
 }
@@ -103,6 +180,7 @@ impl Query {
 /// # Errors
 ///
 /// - Can't open open file
+/// - Can't read a line of the file
 ///
 pub fn read_queries<P: AsRef<std::path::Path> + std::fmt::Debug>(
     qry_file: P,
@@ -112,9 +190,10 @@ pub fn read_queries<P: AsRef<std::path::Path> + std::fmt::Debug>(
     let qry_file = std::io::BufReader::new(qry_file);
     let mut queries: Vec<Query> = qry_file
         .lines()
-        .filter_map(std::result::Result::ok)
+        .collect::<std::io::Result<Vec<_>>>()?
+        .iter()
         .filter_map(|l| l.parse::<Query>().ok())
-        .filter(|q| q.tokens.len() > 0) // throws away any 0-length queries
+        .filter(|q| !q.tokens.is_empty()) // throws away any 0-length queries
         .collect();
 
     //  Re-scale to max range or 1
@@ -122,7 +201,9 @@ pub fn read_queries<P: AsRef<std::path::Path> + std::fmt::Debug>(
         if weighted {
             query.rescale(MAX_TERM_WEIGHT);
         } else {
+            // every term counts once, so scores need no rescaling
             query.rescale(1);
+            query.weight_scale = 1.0;
         }
     }
 
@@ -142,20 +223,25 @@ pub mod tests {
                 Term {
                     token: "this".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "no".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "has".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "colon".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
             ],
+            weight_scale: 1.0,
         };
         assert_eq!(expected, query.parse::<Query>().unwrap());
 
@@ -166,24 +252,30 @@ pub mod tests {
                 Term {
                     token: "this".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "one".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "has".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "colon".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: ":".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
             ],
+            weight_scale: 1.0,
         };
         assert_eq!(expected, query.parse::<Query>().unwrap());
 
@@ -194,25 +286,69 @@ pub mod tests {
                 Term {
                     token: "this".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "many".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "has".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: "colons".to_string(),
                     freq: 1,
+                    kind: TermKind::Positive,
                 },
                 Term {
                     token: ":".to_string(),
                     freq: 6,
+                    kind: TermKind::Positive,
                 },
             ],
+            weight_scale: 1.0,
         };
         assert_eq!(expected, query.parse::<Query>().unwrap());
     }
+
+    #[test]
+    fn rescaling_remembers_weight_scale() {
+        let term = |token: &str, freq| Term {
+            token: token.to_string(),
+            freq,
+            kind: TermKind::Positive,
+        };
+        let query = Query::with_rescale(1, vec![term("a", 64), term("b", 16)], 32);
+        let freqs: Vec<_> = query.tokens.iter().map(|t| t.freq).collect();
+        assert_eq!(freqs, vec![32, 8]);
+        assert!((query.weight_scale - 2.0).abs() < f32::EPSILON);
+
+        let query = Query::with_rescale(1, vec![term("a", 4)], 32);
+        assert!((query.weight_scale - 1.0).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn parse_term_kinds() {
+        let query = r"114:cat -dog ~bird ~bird - ~ \-lrb- \\x \";
+        let parsed = query.parse::<Query>().unwrap();
+        let kind_of = |token: &str| {
+            parsed
+                .tokens
+                .iter()
+                .filter(|t| t.token == token)
+                .map(|t| (t.kind, t.freq))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kind_of("cat"), vec![(TermKind::Positive, 1)]);
+        assert_eq!(kind_of("dog"), vec![(TermKind::Exclude, 1)]);
+        assert_eq!(kind_of("bird"), vec![(TermKind::Negative, 2)]);
+        assert_eq!(kind_of("-"), vec![(TermKind::Positive, 1)]);
+        assert_eq!(kind_of("~"), vec![(TermKind::Positive, 1)]);
+        assert_eq!(kind_of("-lrb-"), vec![(TermKind::Positive, 1)]);
+        assert_eq!(kind_of(r"\x"), vec![(TermKind::Positive, 1)]);
+        assert_eq!(kind_of(r"\"), vec![(TermKind::Positive, 1)]);
+    }
 }
@@ -1,6 +1,6 @@
 use std::ops::Index;
 
-#[derive(Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Byte {
     start: usize,
     stop: usize,
@@ -5,7 +5,7 @@ pub trait Scorer: Send + Sync + Copy {
     fn needs_quantization(&self) -> bool;
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
 pub struct BM25 {
     k1: f32,
     b: f32,
@@ -37,7 +37,7 @@ impl Scorer for BM25 {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
 pub struct Identity {}
 
 impl Identity {
@@ -62,9 +62,46 @@ impl Scorer for Identity {
     }
 }
 
-#[derive(Clone, Copy, Debug)]
+/// The scorer an index was built with, kept to score documents again exactly
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
+pub enum IndexScorer {
+    BM25(BM25),
+    Identity(Identity),
+}
+
+impl From<BM25> for IndexScorer {
+    fn from(scorer: BM25) -> Self {
+        IndexScorer::BM25(scorer)
+    }
+}
+
+impl From<Identity> for IndexScorer {
+    fn from(scorer: Identity) -> Self {
+        IndexScorer::Identity(scorer)
+    }
+}
+
+impl Scorer for IndexScorer {
+    fn score(&self, term_freq: u32, doc_freq: u32, norm_doc_len: f32, num_docs: u32) -> f32 {
+        match self {
+            IndexScorer::BM25(scorer) => scorer.score(term_freq, doc_freq, norm_doc_len, num_docs),
+            IndexScorer::Identity(scorer) => {
+                scorer.score(term_freq, doc_freq, norm_doc_len, num_docs)
+            }
+        }
+    }
+    fn needs_quantization(&self) -> bool {
+        match self {
+            IndexScorer::BM25(scorer) => scorer.needs_quantization(),
+            IndexScorer::Identity(scorer) => scorer.needs_quantization(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
 pub struct LinearQuantizer {
     global_max: f32,
+    quant_bits: u32,
     scale: f32,
 }
 
@@ -73,10 +110,21 @@ impl LinearQuantizer {
     pub fn new(global_max: f32, quant_bits: u32) -> LinearQuantizer {
         LinearQuantizer {
             global_max,
+            quant_bits,
             scale: (1_u32 << (quant_bits)) as f32 / global_max,
         }
     }
 
+    #[must_use]
+    pub fn global_max(&self) -> f32 {
+        self.global_max
+    }
+
+    #[must_use]
+    pub fn quant_bits(&self) -> u32 {
+        self.quant_bits
+    }
+
     /// Quantize the score
     ///
     /// # Panics
@@ -86,4 +134,18 @@ impl LinearQuantizer {
         assert!(score >= 0_f32 && score <= self.global_max);
         (score * self.scale).ceil() as u32
     }
+
+    /// Approximate original score of a quantized score
+    #[must_use]
+    pub fn dequantize(self, quantized: u32) -> f32 {
+        quantized as f32 / self.scale
+    }
+
+    /// Quantize the score, clamping it to the global max first
+    ///
+    /// Used for documents scored after the global max was determined.
+    #[must_use]
+    pub fn quantize_clamped(self, score: f32) -> u32 {
+        self.quantize(score.clamp(0_f32, self.global_max))
+    }
 }
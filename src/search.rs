@@ -4,6 +4,7 @@ pub const CHUNK_SIZE: usize = 128;
 pub const CHUNK_SHIFT: usize = CHUNK_SIZE.ilog2() as usize;
 
 use crate::{
+    bitmap::Bitmap,
     compress::{self},
     impact, ScoreType,
 };
@@ -11,38 +12,290 @@ use crate::{
 #[derive(Debug)]
 pub struct Scratch {
     pub impacts: Vec<Vec<impact::Impact>>,
+    pub penalties: Vec<impact::Impact>,
+    pub exclusions: Vec<impact::Impact>,
     pub large_decode_buf: compress::LargeBuffer,
     pub decode_buf: compress::Buffer,
     pub chunk: Vec<ScoreType>,
     pub accumulators: Vec<ScoreType>,
+    // Accumulators start here so that negative terms can be subtracted
+    pub offset: ScoreType,
+    pub excluded: Bitmap,
+    // The documents set in `excluded`, so that resetting it doesn't scan the whole bitmap
+    pub excluded_docs: Vec<u32>,
+    // (term, impact) of every positive impact segment, grouped by term
+    pub term_levels: Vec<(usize, ScoreType)>,
     pub heap: BinaryHeap<Result>,
+    // Only the accumulator pages touched by the query are initialized and scanned
+    pub sparse: bool,
+    pub pages: DirtyPages,
 }
 
 impl Scratch {
     pub fn from_index(max_level: usize, max_weight: usize, max_doc_id: u32) -> Self {
         Self {
             impacts: (0..=max_level * max_weight).map(|_| Vec::new()).collect(),
+            penalties: Vec::new(),
+            exclusions: Vec::new(),
             accumulators: vec![0; max_doc_id as usize + 1],
             chunk: vec![0; ((max_doc_id as usize + 1) >> CHUNK_SHIFT) + 1],
+            offset: 0,
+            excluded: Bitmap::with_capacity(max_doc_id as usize + 1),
+            excluded_docs: Vec::new(),
+            term_levels: Vec::new(),
             large_decode_buf: [0; compress::LARGE_BLOCK_LEN],
             decode_buf: [0; compress::BLOCK_LEN],
             heap: BinaryHeap::with_capacity(10000),
+            sparse: false,
+            pages: DirtyPages::with_capacity(max_doc_id as usize + 1),
         }
     }
+
+    /// Upper bound of the positive score of a document, the sum of the largest impact of each
+    /// positive query term
+    #[must_use]
+    pub fn max_score(&self) -> u32 {
+        let mut max_levels: Vec<(usize, ScoreType)> = Vec::new();
+        for &(term, level) in &self.term_levels {
+            match max_levels
+                .iter_mut()
+                .find(|(max_term, _)| *max_term == term)
+            {
+                Some((_, max)) => *max = (*max).max(level),
+                None => max_levels.push((term, level)),
+            }
+        }
+        max_levels.iter().map(|&(_, max)| u32::from(max)).sum()
+    }
+
+    /// Lowers the offset so that the offset plus the largest positive score fit the accumulators
+    ///
+    /// Penalties are then capped at the offset, so documents with larger penalties score higher
+    /// than they should. Returns whether the offset was lowered.
+    pub fn fit_offset(&mut self) -> bool {
+        let headroom = u32::from(ScoreType::MAX).saturating_sub(self.max_score());
+        if u32::from(self.offset) > headroom {
+            self.offset = headroom as ScoreType;
+            return true;
+        }
+        false
+    }
+
+    /// Unmarks the documents excluded by the previous query
+    pub fn clear_excluded(&mut self) {
+        for &doc_id in &self.excluded_docs {
+            self.excluded.remove(doc_id);
+        }
+        self.excluded_docs.clear();
+    }
+
+    /// Scales the impacts of the query's segments by `weight`, e.g. to combine the scores of
+    /// several indexes
+    pub fn weight_impacts(&mut self, weight: f32) {
+        // float to integer casts saturate
+        let scale = |impact: u16| (f32::from(impact) * weight).round() as u16;
+        for segment in self.impacts.iter_mut().flatten().chain(&mut self.penalties) {
+            segment.meta_data.impact = scale(segment.meta_data.impact);
+        }
+        for (_, level) in &mut self.term_levels {
+            *level = scale(*level);
+        }
+        // the accumulators start high enough to absorb the largest weighted penalty of each term
+        let mut max_penalties: Vec<(usize, ScoreType)> = Vec::new();
+        for penalty in &self.penalties {
+            match max_penalties
+                .iter_mut()
+                .find(|(term, _)| *term == penalty.term)
+            {
+                Some((_, max)) => *max = (*max).max(penalty.impact()),
+                None => max_penalties.push((penalty.term, penalty.impact())),
+            }
+        }
+        self.offset = max_penalties
+            .iter()
+            .fold(0, |offset: ScoreType, &(_, max)| offset.saturating_add(max));
+    }
+
+    /// Exchanges the accumulators and what is tracked about them with `other`
+    pub fn swap_accumulators(&mut self, other: &mut Scratch) {
+        std::mem::swap(&mut self.accumulators, &mut other.accumulators);
+        std::mem::swap(&mut self.chunk, &mut other.chunk);
+        std::mem::swap(&mut self.offset, &mut other.offset);
+        std::mem::swap(&mut self.excluded, &mut other.excluded);
+        std::mem::swap(&mut self.excluded_docs, &mut other.excluded_docs);
+        std::mem::swap(&mut self.sparse, &mut other.sparse);
+        std::mem::swap(&mut self.pages, &mut other.pages);
+    }
+}
+
+/// Tracks which pages of `CHUNK_SIZE` accumulators a query has touched
+///
+/// A page is initialized when it is first touched, so queries with few postings don't pay for
+/// resetting all accumulators.
+#[derive(Debug)]
+pub struct DirtyPages {
+    dirty: Bitmap,
+    touched: Vec<u32>,
+}
+
+impl DirtyPages {
+    fn with_capacity(num_docs: usize) -> Self {
+        Self {
+            dirty: Bitmap::with_capacity((num_docs >> CHUNK_SHIFT) + 1),
+            touched: Vec::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for &page in &self.touched {
+            self.dirty.remove(page);
+        }
+        self.touched.clear();
+    }
+
+    /// Initializes the page of `doc_id` to `offset` unless it was touched before
+    #[inline]
+    pub fn touch(
+        &mut self,
+        doc_id: usize,
+        accumulators: &mut [ScoreType],
+        chunks: &mut [ScoreType],
+        offset: ScoreType,
+    ) {
+        let page = doc_id >> CHUNK_SHIFT;
+        if !self.dirty.contains(page as u32) {
+            self.dirty.insert(page as u32);
+            self.touched.push(page as u32);
+            let start = page << CHUNK_SHIFT;
+            let end = (start + CHUNK_SIZE).min(accumulators.len());
+            accumulators[start..end].fill(offset);
+            chunks[page] = 0;
+        }
+    }
+
+    /// The touched pages in the order they were first touched
+    pub fn touched(&self) -> &[u32] {
+        &self.touched
+    }
+}
+
+/// How much of the postings of a query to process
+#[derive(Copy, Clone, PartialEq, serde::Serialize, serde::Deserialize, Debug)]
+pub enum QueryMode {
+    /// Process a fraction of the postings of the query
+    Fraction(f32),
+    /// Process a fixed number of postings
+    Fixed(i64),
+    /// Process postings until the top-k documents can no longer change
+    RankSafe,
+    /// Process postings until the time since the query started runs out
+    Deadline(#[serde(with = "millis")] std::time::Duration),
+    /// Process as many postings as the cost model of the index estimates to fit the latency
+    Target(#[serde(with = "millis")] std::time::Duration),
+    /// Process a fixed number of postings of each query term
+    TermCap(i64),
+    /// Process a fixed number of impact segments
+    Segments(usize),
+    /// Process the impact segments with at least the given weighted impact
+    MinImpact(u16),
+}
+
+impl std::str::FromStr for QueryMode {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "safe" {
+            return Ok(QueryMode::RankSafe);
+        }
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.len() != 2 {
+            return Err(anyhow::anyhow!("invalid query mode"));
+        }
+        match parts[0] {
+            "fraction" => {
+                let rho = parts[1].parse::<f32>()?;
+                if (0.0..=1.0).contains(&rho) {
+                    Ok(QueryMode::Fraction(rho))
+                } else {
+                    Err(anyhow::anyhow!("Rho must be in range [0.0, 1.0]"))
+                }
+            }
+            "fixed" => {
+                let budget = parts[1].parse::<i64>()?;
+                Ok(QueryMode::Fixed(budget))
+            }
+            "deadline" => {
+                let millis = parts[1].parse::<f64>()?;
+                let deadline = std::time::Duration::try_from_secs_f64(millis / 1000.0)?;
+                Ok(QueryMode::Deadline(deadline))
+            }
+            "target" => {
+                let millis = parts[1].parse::<f64>()?;
+                let target = std::time::Duration::try_from_secs_f64(millis / 1000.0)?;
+                Ok(QueryMode::Target(target))
+            }
+            "termcap" => Ok(QueryMode::TermCap(parts[1].parse::<i64>()?)),
+            "segments" => Ok(QueryMode::Segments(parts[1].parse::<usize>()?)),
+            "minimpact" => Ok(QueryMode::MinImpact(parts[1].parse::<u16>()?)),
+            _ => Err(anyhow::anyhow!("invalid query mode")),
+        }
+    }
+}
+
+/// Formats the mode the way `from_str` parses it
+impl std::fmt::Display for QueryMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let millis = |duration: &std::time::Duration| duration.as_secs_f64() * 1000.0;
+        match self {
+            QueryMode::Fraction(rho) => write!(f, "fraction-{}", rho),
+            QueryMode::Fixed(budget) => write!(f, "fixed-{}", budget),
+            QueryMode::RankSafe => write!(f, "safe"),
+            QueryMode::Deadline(deadline) => write!(f, "deadline-{}", millis(deadline)),
+            QueryMode::Target(target) => write!(f, "target-{}", millis(target)),
+            QueryMode::TermCap(budget) => write!(f, "termcap-{}", budget),
+            QueryMode::Segments(count) => write!(f, "segments-{}", count),
+            QueryMode::MinImpact(impact) => write!(f, "minimpact-{}", impact),
+        }
+    }
+}
+
+/// (De)serializes durations as fractional milliseconds
+mod millis {
+    pub fn serialize<S: serde::Serializer>(
+        duration: &std::time::Duration,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(duration.as_secs_f64() * 1000.0)
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<std::time::Duration, D::Error> {
+        let millis = <f64 as serde::Deserialize>::deserialize(deserializer)?;
+        std::time::Duration::try_from_secs_f64(millis / 1000.0).map_err(serde::de::Error::custom)
+    }
 }
 
 use std::cmp::Ordering;
 use std::io::Write;
 
-#[derive(Eq, serde::Serialize, serde::Deserialize, Debug)]
+/// A scored document, ordered from best to worst
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
 pub struct Result {
     pub doc_id: u32,
     pub score: crate::ScoreType,
+    /// Orders documents with equal scores, lower keys rank first
+    #[serde(skip)]
+    pub tie: u64,
+    /// Approximate score before quantization, see `Index::add_real_scores`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub real_score: Option<f32>,
 }
 
+impl Eq for Result {}
+
 impl Ord for Result {
     fn cmp(&self, other: &Self) -> Ordering {
-        other.score.cmp(&self.score)
+        other.score.cmp(&self.score).then(self.tie.cmp(&other.tie))
     }
 }
 
@@ -54,7 +307,7 @@ impl PartialOrd for Result {
 
 impl PartialEq for Result {
     fn eq(&self, other: &Self) -> bool {
-        self.score == other.score
+        self.score == other.score && self.tie == other.tie
     }
 }
 
@@ -63,6 +316,75 @@ pub struct Results {
     pub topk: Vec<Result>,
     pub took: std::time::Duration,
     pub qid: usize,
+    /// The top-k documents are guaranteed to be the same set as with exhaustive processing
+    ///
+    /// After rank-safe early termination their scores may still be lower and their order within
+    /// the top-k may differ.
+    #[serde(default)]
+    pub exact: bool,
+    /// Number of documents with a positive score, of which `topk` holds the best ones
+    #[serde(default)]
+    pub matched: usize,
+    #[serde(default)]
+    pub stats: Stats,
+}
+
+/// What happened while processing a query
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize, Debug)]
+pub struct Stats {
+    /// Postings of the positive query terms
+    pub total_postings: usize,
+    pub processed_postings: usize,
+    pub processed_segments: usize,
+    /// Whether a limit of the query mode stopped processing before the top-k was exact
+    pub budget_exhausted: bool,
+    /// Query terms missing from the index
+    pub unknown_terms: Vec<String>,
+    /// Time spent finding the impact segments to process
+    pub planning: std::time::Duration,
+    /// Time spent initializing accumulators and processing postings
+    ///
+    /// Parallel queries select the top-k of each document range during accumulation.
+    pub accumulation: std::time::Duration,
+    /// Time spent selecting the top-k documents
+    pub topk: std::time::Duration,
+}
+
+/// How a document was scored by a query, see `Index::explain`
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Explanation {
+    pub doc_id: u32,
+    pub terms: Vec<TermExplanation>,
+    /// Score the document receives from the processed impact segments
+    pub score: i64,
+    /// Whether an exclusion term or a deletion removes the document from the results
+    pub excluded: bool,
+}
+
+/// Contribution of one query term to the score of a document
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct TermExplanation {
+    pub token: String,
+    pub kind: crate::query::TermKind,
+    /// Weight the impacts of the term are multiplied by
+    pub query_weight: u32,
+    /// The impact segment of the term holding the document, if any
+    pub segment: Option<SegmentExplanation>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct SegmentExplanation {
+    /// Index segment holding the postings, 0 unless documents were appended
+    pub index_segment: usize,
+    /// Position among the impact segments of the term, highest impact first
+    pub position: usize,
+    pub postings: u32,
+    /// Impact stored in the index
+    pub impact: u16,
+    /// Impact after applying the query weight
+    pub weighted_impact: u16,
+    /// Whether the query mode processes the segment
+    pub processed: bool,
 }
 
 impl Results {
@@ -74,7 +396,8 @@ impl Results {
                 self.qid,
                 id_map[res.doc_id as usize],
                 rank + 1,
-                res.score
+                res.real_score
+                    .map_or(res.score.to_string(), |s| s.to_string())
             )
             .unwrap();
         }
@@ -106,3 +429,84 @@ impl std::fmt::Display for Results {
         write!(f, "]")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_query_modes() {
+        assert_eq!(
+            "fraction-0.5".parse::<QueryMode>().unwrap(),
+            QueryMode::Fraction(0.5)
+        );
+        assert_eq!(
+            "fixed-1000".parse::<QueryMode>().unwrap(),
+            QueryMode::Fixed(1000)
+        );
+        assert_eq!("safe".parse::<QueryMode>().unwrap(), QueryMode::RankSafe);
+        assert_eq!(
+            "deadline-2.5".parse::<QueryMode>().unwrap(),
+            QueryMode::Deadline(std::time::Duration::from_micros(2500))
+        );
+        assert!("deadline-x".parse::<QueryMode>().is_err());
+        assert_eq!(
+            "termcap-500".parse::<QueryMode>().unwrap(),
+            QueryMode::TermCap(500)
+        );
+        assert_eq!(
+            "segments-20".parse::<QueryMode>().unwrap(),
+            QueryMode::Segments(20)
+        );
+        assert_eq!(
+            "minimpact-3".parse::<QueryMode>().unwrap(),
+            QueryMode::MinImpact(3)
+        );
+        assert_eq!(
+            "target-10".parse::<QueryMode>().unwrap(),
+            QueryMode::Target(std::time::Duration::from_millis(10))
+        );
+        assert!("fraction-1.5".parse::<QueryMode>().is_err());
+        assert!("fixed".parse::<QueryMode>().is_err());
+        assert!("exhaustive-1".parse::<QueryMode>().is_err());
+    }
+
+    #[test]
+    fn query_modes_display_as_parsed() {
+        for mode in [
+            "fraction-0.1",
+            "fixed-1000",
+            "safe",
+            "deadline-2.5",
+            "target-10",
+            "termcap-500",
+            "segments-20",
+            "minimpact-3",
+        ] {
+            assert_eq!(mode.parse::<QueryMode>().unwrap().to_string(), mode);
+        }
+    }
+
+    #[test]
+    fn deadline_is_given_in_milliseconds() {
+        let mode: QueryMode = serde_json::from_str(r#"{"Deadline":5}"#).unwrap();
+        assert_eq!(
+            mode,
+            QueryMode::Deadline(std::time::Duration::from_millis(5))
+        );
+        assert_eq!(serde_json::to_string(&mode).unwrap(), r#"{"Deadline":5.0}"#);
+    }
+
+    #[test]
+    fn offsets_are_lowered_to_fit_the_accumulators() {
+        let mut scratch = Scratch::from_index(1, 1, 10);
+        scratch.term_levels = vec![(0, 40000), (0, 100), (1, 20000)];
+        assert_eq!(scratch.max_score(), 60000);
+        scratch.offset = 5000;
+        assert!(!scratch.fit_offset());
+        assert_eq!(scratch.offset, 5000);
+        scratch.offset = 10000;
+        assert!(scratch.fit_offset());
+        assert_eq!(scratch.offset, 5535);
+    }
+}
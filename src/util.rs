@@ -9,6 +9,97 @@ pub fn progress_bar(name: &str, limit: usize) -> indicatif::ProgressBar {
     pb
 }
 
+/// The magic bytes and format version that a kind of file starts with
+#[derive(Debug)]
+pub(crate) struct FileHeader {
+    /// Name of the kind of file in error messages
+    pub kind: &'static str,
+    pub magic: &'static [u8; 4],
+    /// Incremented whenever the serialized layout of the file changes
+    pub version: u32,
+    /// How to create a file of the current version
+    pub create_with: &'static str,
+}
+
+impl FileHeader {
+    /// Writes the magic bytes followed by the format version
+    ///
+    /// # Errors
+    /// - fails if the header can't be written
+    pub fn write(&self, output: &mut impl std::io::Write) -> std::io::Result<()> {
+        output.write_all(self.magic)?;
+        output.write_all(&self.version.to_le_bytes())
+    }
+
+    /// Reads the header of the file `file_name` from `input`
+    ///
+    /// # Errors
+    /// - fails if the file does not start with the magic bytes, e.g. because it was written
+    ///   before files of this kind were versioned
+    /// - fails if the file has another format version
+    pub fn check(
+        &self,
+        input: &mut impl std::io::Read,
+        file_name: &impl std::fmt::Debug,
+    ) -> anyhow::Result<()> {
+        let mut header = [0; 8];
+        let has_magic = input.read_exact(&mut header).is_ok() && header[..4] == self.magic[..];
+        if !has_magic {
+            anyhow::bail!(
+                "{:?} is not an ioqp {}, or was written by a version without versioned {} \
+                 files; recreate it with {}",
+                file_name,
+                self.kind,
+                self.kind,
+                self.create_with
+            );
+        }
+        let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        if version != self.version {
+            anyhow::bail!(
+                "{:?} has {} format version {} but version {} is required; recreate it with {}",
+                file_name,
+                self.kind,
+                version,
+                self.version,
+                self.create_with
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The `p`-th percentile of the `sorted` values using the nearest-rank method
+///
+/// Returns `None` if there are no values.
+#[must_use]
+pub fn percentile(sorted: &[u64], p: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted.get(rank.clamp(1, sorted.len()) - 1).copied()
+}
+
+/// A pool with a thread for each partition of each of `concurrency` queries processed at once,
+/// if queries are split into more than one partition
+///
+/// # Errors
+/// - The threads can't be spawned
+pub fn partition_pool(
+    concurrency: usize,
+    parallelism: usize,
+) -> anyhow::Result<Option<rayon::ThreadPool>> {
+    if parallelism <= 1 {
+        return Ok(None);
+    }
+    Ok(Some(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(concurrency.max(1).saturating_mul(parallelism))
+            .build()?,
+    ))
+}
+
 #[cfg(target_feature = "avx2")]
 /// determine max fast
 ///
@@ -61,3 +152,31 @@ pub unsafe fn _determine_max_i16(scores: &[i16], threshold: i16) -> i16 {
 pub(crate) fn _determine_max(scores: &[ScoreType], threshold: ScoreType) -> ScoreType {
     scores.iter().copied().max().unwrap().max(threshold)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let values: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&values, 0.5), Some(50));
+        assert_eq!(percentile(&values, 0.99), Some(99));
+        assert_eq!(percentile(&values, 0.999), Some(100));
+        assert_eq!(percentile(&values, 0.0), Some(1));
+        assert_eq!(percentile(&[7], 0.9), Some(7));
+        assert_eq!(percentile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn partition_pools_have_threads_for_partitions() {
+        let num_threads = |concurrency, parallelism| {
+            let pool = partition_pool(concurrency, parallelism).unwrap();
+            pool.map(|pool| pool.current_num_threads())
+        };
+        // the default of one query at a time still runs its partitions in parallel
+        assert_eq!(num_threads(1, 4), Some(4));
+        assert_eq!(num_threads(3, 2), Some(6));
+        assert_eq!(num_threads(2, 1), None);
+    }
+}